@@ -0,0 +1,25 @@
+//! Runs a local message broker that `UnixSocketBus` publishers and
+//! `UnixSocketSubscriber`s connect to.
+//!
+//! Usage: `bus_broker <socket>`
+
+use std::{env, process};
+
+use unit_testing_ppp::ch_09::bus::unix_socket::{Broker, BrokerOptions};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let [_, socket] = args.as_slice() else {
+        eprintln!("usage: bus_broker <socket>");
+        process::exit(2);
+    };
+
+    let result = Broker::bind(socket, BrokerOptions::default()).and_then(|broker| {
+        println!("listening on {}", socket);
+        broker.run()
+    });
+    if let Err(e) = result {
+        eprintln!("broker failed: {}", e);
+        process::exit(1);
+    }
+}
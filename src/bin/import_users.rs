@@ -0,0 +1,66 @@
+//! Imports users from a CSV of email addresses into a user database.
+//!
+//! Usage: `import_users <database> <csv> [spool]`
+//!
+//! Messages raised by the import, such as the confirmation requests of the new
+//! users, are spooled to `spool`, `<database>.spool` by default, as JSON envelopes
+//! like `users` does.
+
+use std::{env, fs, process};
+
+use unit_testing_ppp::ch_09::bus::file_spool::FileSpoolBus;
+use unit_testing_ppp::ch_09::bus_message::MessageFormat;
+use unit_testing_ppp::ch_09::domain_logger::TracingDomainLogger;
+use unit_testing_ppp::ch_09::sqlite_database::SQLiteDatabase;
+use unit_testing_ppp::ch_09::types::{EventDispatcher, MessageBus};
+use unit_testing_ppp::ch_09::user_import::import_users;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let (database, csv, spool) = match args.as_slice() {
+        [_, database, csv] => (database, csv, format!("{}.spool", database)),
+        [_, database, csv, spool] => (database, csv, spool.clone()),
+        _ => {
+            eprintln!("usage: import_users <database> <csv> [spool]");
+            process::exit(2);
+        }
+    };
+
+    if let Err(e) = run(database, csv, &spool) {
+        eprintln!("import failed: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(database: &str, csv: &str, spool: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let database = SQLiteDatabase::open(database)?;
+    let csv = fs::read_to_string(csv)?;
+    let event_dispatcher = EventDispatcher::new(
+        MessageBus::with_format(FileSpoolBus::new(spool)?, MessageFormat::Json),
+        TracingDomainLogger,
+    );
+
+    let report = import_users(&database, &event_dispatcher, &csv)?;
+    for row in &report.rows {
+        match &row.result {
+            Ok((user_id, user_type)) => {
+                println!(
+                    "line {}: {} imported as user {} ({})",
+                    row.line, row.email, user_id, user_type
+                )
+            }
+            Err(e) => println!("line {}: {} skipped: {}", row.line, row.email, e),
+        }
+    }
+    println!(
+        "{} imported, {} skipped",
+        report.imported(),
+        report.failed()
+    );
+
+    if report.failed() > 0 {
+        process::exit(1);
+    }
+
+    Ok(())
+}
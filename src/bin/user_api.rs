@@ -0,0 +1,61 @@
+//! Serves the user management HTTP API.
+//!
+//! Usage: `user_api <database> <bus-socket> [address]`
+//!
+//! Messages are published to the `bus_broker` listening on `bus-socket`, and
+//! user type changes are logged as `tracing` events. Requests share a pool of
+//! connections to the database, and users and the company are cached in memory
+//! for up to a minute.
+
+use std::{env, process};
+
+use unit_testing_ppp::ch_09::asynchronous::{AsyncUserController, BlockingDatabase};
+use unit_testing_ppp::ch_09::bus::unix_socket::UnixSocketBus;
+use unit_testing_ppp::ch_09::caching_database::{CacheOptions, CachingDatabase};
+use unit_testing_ppp::ch_09::domain_logger::TracingDomainLogger;
+use unit_testing_ppp::ch_09::http::router;
+use unit_testing_ppp::ch_09::pooled_database::{PoolOptions, PooledSQLiteDatabase};
+use unit_testing_ppp::ch_09::types::{EventDispatcher, MessageBus};
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:3000";
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().collect();
+    let (database, socket, address) = match args.as_slice() {
+        [_, database, socket] => (database, socket, DEFAULT_ADDRESS),
+        [_, database, socket, address] => (database, socket, address.as_str()),
+        _ => {
+            eprintln!("usage: user_api <database> <bus-socket> [address]");
+            process::exit(2);
+        }
+    };
+
+    if let Err(e) = run(database, socket, address).await {
+        eprintln!("server failed: {}", e);
+        process::exit(1);
+    }
+}
+
+async fn run(
+    database: &str,
+    socket: &str,
+    address: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let controller = AsyncUserController::new(
+        BlockingDatabase::new(CachingDatabase::new(
+            PooledSQLiteDatabase::open(database, PoolOptions::default())?,
+            CacheOptions::default(),
+        )),
+        EventDispatcher::new(
+            MessageBus::new(UnixSocketBus::new(socket)),
+            TracingDomainLogger,
+        ),
+    );
+
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    println!("listening on {}", listener.local_addr()?);
+    axum::serve(listener, router(controller)).await?;
+
+    Ok(())
+}
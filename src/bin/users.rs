@@ -0,0 +1,492 @@
+//! Administers users and companies in a user database.
+//!
+//! Usage: `users [--json] [--spool <dir>] [--format <json|legacy>] <database> <command> [args]`
+//!
+//! Commands:
+//!   create-company <domain>
+//!   create-user <email>
+//!   show-user <user-id>
+//!   change-email <user-id> <email> [actor]
+//!   deactivate|reactivate|delete <user-id>
+//!   history <user-id>
+//!   employees [domain]
+//!   reconcile [--fix]
+//!
+//! The use cases act on a single company, so only one can be created. `employees`
+//! lists that company's employees unless given the domain of another one.
+//!
+//! Messages raised by changes are spooled to `<dir>`, `<database>.spool` by
+//! default, for delivery by whatever consumes the spool. They are JSON envelopes
+//! unless `--format legacy` asks for the text format, which only email changes
+//! have.
+
+use std::{env, process};
+
+use serde_json::{json, Value};
+use unit_testing_ppp::ch_09::bus::file_spool::FileSpoolBus;
+use unit_testing_ppp::ch_09::bus_message::MessageFormat;
+use unit_testing_ppp::ch_09::domain_logger::TracingDomainLogger;
+use unit_testing_ppp::ch_09::email_history::SYSTEM_ACTOR;
+use unit_testing_ppp::ch_09::http::{CompanyResponse, UserResponse};
+use unit_testing_ppp::ch_09::reconciliation::{EmployeeCountReconciler, ReconciliationMode};
+use unit_testing_ppp::ch_09::sqlite_database::SQLiteDatabase;
+use unit_testing_ppp::ch_09::types::{
+    Database, EventDispatcher, MessageBus, UserController, UserManagementError,
+};
+
+const USAGE: &str =
+    "usage: users [--json] [--spool <dir>] [--format <json|legacy>] <database> <command> [args]
+
+commands:
+  create-company <domain>
+  create-user <email>
+  show-user <user-id>
+  change-email <user-id> <email> [actor]
+  deactivate|reactivate|delete <user-id>
+  history <user-id>
+  employees [domain]
+  reconcile [--fix]";
+
+/// What a command prints: `json` with `--json`, `text` otherwise.
+struct Output {
+    json: Value,
+    text: String,
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let as_json = take_flag(&mut args, "--json");
+    let spool = take_option(&mut args, "--spool");
+    let format = match take_option(&mut args, "--format").as_deref() {
+        None | Some("json") => Some(MessageFormat::Json),
+        Some("legacy") => Some(MessageFormat::Legacy),
+        Some(_) => None,
+    };
+
+    let (Some((database, command)), Some(format)) = (
+        args.split_first()
+            .filter(|(_, command)| !command.is_empty()),
+        format,
+    ) else {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    };
+    let spool = spool.unwrap_or_else(|| format!("{}.spool", database));
+
+    match run(database, &spool, format, command) {
+        Ok(output) if as_json => println!("{}", output.json),
+        Ok(output) => println!("{}", output.text),
+        Err(e) => {
+            if as_json {
+                println!("{}", json!({ "error": e.to_string() }));
+            } else {
+                eprintln!("error: {}", e);
+            }
+            process::exit(1);
+        }
+    }
+}
+
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let found = args.iter().any(|arg| arg == flag);
+    args.retain(|arg| arg != flag);
+
+    found
+}
+
+fn take_option(args: &mut Vec<String>, option: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == option)?;
+    args.remove(index);
+
+    (index < args.len()).then(|| args.remove(index))
+}
+
+fn run(
+    database: &str,
+    spool: &str,
+    format: MessageFormat,
+    command: &[String],
+) -> Result<Output, Box<dyn std::error::Error>> {
+    let database = SQLiteDatabase::open(database)?;
+    let event_dispatcher = || -> Result<EventDispatcher, Box<dyn std::error::Error>> {
+        Ok(EventDispatcher::new(
+            MessageBus::with_format(FileSpoolBus::new(spool)?, format),
+            TracingDomainLogger,
+        ))
+    };
+    let args: Vec<&str> = command.iter().map(String::as_str).collect();
+
+    let output = match args.as_slice() {
+        ["create-company", domain] => {
+            if let Some(company) = database.get_company()? {
+                return Err(format!("company {} already exists", company.domain_name).into());
+            }
+            let company_id = database.insert_company(domain)?;
+            Output {
+                json: json!({ "company_id": company_id }),
+                text: format!("created company {} for {}", company_id, domain),
+            }
+        }
+        ["create-user", email] => {
+            let controller = UserController::new(database, event_dispatcher()?);
+            let user_id = controller.register_user(email)?;
+            let user_type = controller
+                .database
+                .get_user_by_id(user_id)?
+                .ok_or(UserManagementError::UserNotFound(user_id))?
+                .user_type;
+            Output {
+                json: json!({ "user_id": user_id, "user_type": user_type }),
+                text: format!("created user {} ({})", user_id, user_type),
+            }
+        }
+        ["show-user", user_id] => {
+            let user_id = user_id.parse()?;
+            let user = database
+                .get_user_by_id(user_id)?
+                .ok_or(UserManagementError::UserNotFound(user_id))?;
+            let user = UserResponse::from(user);
+            Output {
+                text: format!(
+                    "{}\t{}\t{}\tconfirmed: {}",
+                    user.user_id, user.email, user.user_type, user.email_confirmed
+                ),
+                json: serde_json::to_value(user)?,
+            }
+        }
+        ["change-email", user_id, email] | ["change-email", user_id, email, _] => {
+            let actor = args.get(3).copied().unwrap_or(SYSTEM_ACTOR);
+            let user_id = user_id.parse()?;
+            let controller = UserController::new(database, event_dispatcher()?);
+            controller.change_email_as(user_id, email, actor)?;
+            Output {
+                json: json!({ "user_id": user_id, "email": email }),
+                text: format!("changed email of user {} to {}", user_id, email),
+            }
+        }
+        [action @ ("deactivate" | "reactivate" | "delete"), user_id] => {
+            let user_id = user_id.parse()?;
+            let controller = UserController::new(database, event_dispatcher()?);
+            match *action {
+                "deactivate" => controller.deactivate_user(user_id)?,
+                "reactivate" => controller.reactivate_user(user_id)?,
+                _ => controller.delete_user(user_id)?,
+            }
+            let user = controller
+                .database
+                .get_user_including_inactive(user_id)?
+                .ok_or(UserManagementError::UserNotFound(user_id))?;
+            Output {
+                json: json!({ "user_id": user_id, "status": user.status }),
+                text: format!("user {} is now {}", user_id, user.status),
+            }
+        }
+        ["history", user_id] => {
+            let history = database.get_email_history(user_id.parse()?)?;
+            Output {
+                text: history
+                    .iter()
+                    .map(|change| {
+                        format!(
+                            "{}\t{} -> {}\t{} -> {}\tby {}",
+                            change.changed_at,
+                            change.old_email,
+                            change.new_email,
+                            change.old_type,
+                            change.new_type,
+                            change.actor
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                json: serde_json::to_value(history)?,
+            }
+        }
+        ["employees"] | ["employees", _] => {
+            let company = match args.get(1) {
+                Some(domain) => database
+                    .get_companies()?
+                    .into_iter()
+                    .find(|company| company.domain_name.eq_ignore_ascii_case(domain)),
+                None => database.get_company()?,
+            }
+            .ok_or(UserManagementError::CompanyNotFound)?;
+            let employees: Vec<UserResponse> = database
+                .get_employees(&company.domain_name)?
+                .into_iter()
+                .map(UserResponse::from)
+                .collect();
+            Output {
+                text: employees
+                    .iter()
+                    .map(|user| format!("{}\t{}\t{}", user.user_id, user.email, user.user_type))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                json: json!({
+                    "company": CompanyResponse::from(company),
+                    "employees": employees,
+                }),
+            }
+        }
+        ["reconcile"] | ["reconcile", "--fix"] => {
+            let mode = if args.len() == 2 {
+                ReconciliationMode::Correct
+            } else {
+                ReconciliationMode::ReportOnly
+            };
+            let event_dispatcher = event_dispatcher()?;
+            let drifts =
+                EmployeeCountReconciler::new(&database, &event_dispatcher).reconcile(mode)?;
+            Output {
+                text: match drifts.is_empty() {
+                    true => "all employee counts are correct".to_owned(),
+                    false => drifts
+                        .iter()
+                        .map(|drift| {
+                            format!(
+                                "{}: recorded {}, actual {}",
+                                drift.domain_name, drift.recorded, drift.actual
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                },
+                json: json!({ "corrected": mode == ReconciliationMode::Correct, "drifts": drifts }),
+            }
+        }
+        _ => return Err(USAGE.into()),
+    };
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use std::error;
+    use std::fs;
+
+    use unit_testing_ppp::ch_09::bus_message::{parse_message, BusMessage};
+    use unit_testing_ppp::ch_09::types::{DomainEvent, UserType};
+    use uuid::Uuid;
+
+    use super::*;
+
+    /// A fresh database path and spool directory.
+    fn paths() -> (String, String) {
+        let database = env::temp_dir().join(format!("users-{}.db", Uuid::new_v4()));
+        let database = database.display().to_string();
+        let spool = format!("{}.spool", database);
+
+        (database, spool)
+    }
+
+    fn users(database: &str, spool: &str, command: &str) -> Result<Output, Box<dyn error::Error>> {
+        users_in(MessageFormat::Json, database, spool, command)
+    }
+
+    fn users_in(
+        format: MessageFormat,
+        database: &str,
+        spool: &str,
+        command: &str,
+    ) -> Result<Output, Box<dyn error::Error>> {
+        let command: Vec<String> = command.split(' ').map(str::to_owned).collect();
+
+        run(database, spool, format, &command)
+    }
+
+    /// The events spooled so far, oldest first.
+    fn spooled_events(spool: &str) -> Result<Vec<DomainEvent>, Box<dyn error::Error>> {
+        FileSpoolBus::new(spool)?
+            .pending()?
+            .iter()
+            .map(|spooled| match parse_message(&spooled.message)? {
+                BusMessage::Envelope(envelope) => Ok(envelope.event),
+                BusMessage::Legacy(event) => Ok(event),
+            })
+            .collect()
+    }
+
+    fn cleanup(database: &str, spool: &str) -> Result<(), Box<dyn error::Error>> {
+        fs::remove_file(database)?;
+        fs::remove_dir_all(spool)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn created_users_are_listed_as_employees() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let (database, spool) = paths();
+        users(&database, &spool, "create-company mycorp.com")?;
+
+        // Act
+        let created = users(&database, &spool, "create-user new@mycorp.com")?;
+        users(&database, &spool, "create-user new@example.com")?;
+        let employees = users(&database, &spool, "employees")?;
+
+        // Assert
+        assert_eq!(
+            json!({ "user_id": 1, "user_type": "EMPLOYEE" }),
+            created.json
+        );
+        assert_eq!("created user 1 (EMPLOYEE)", created.text);
+        assert_eq!("mycorp.com", employees.json["company"]["domain_name"]);
+        assert_eq!(1, employees.json["company"]["number_of_employees"]);
+        assert_eq!("1\tnew@mycorp.com\tEMPLOYEE", employees.text);
+        assert_eq!(4, spooled_events(&spool)?.len());
+
+        cleanup(&database, &spool)
+    }
+
+    #[test]
+    fn only_one_company_can_be_created() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let (database, spool) = paths();
+        users(&database, &spool, "create-company mycorp.com")?;
+
+        // Act
+        let result = users(&database, &spool, "create-company othercorp.com");
+
+        // Assert
+        assert_eq!(
+            "company mycorp.com already exists",
+            result.err().unwrap().to_string()
+        );
+        assert_eq!(1, SQLiteDatabase::open(&database)?.get_companies()?.len());
+
+        fs::remove_file(&database)?;
+        Ok(())
+    }
+
+    #[test]
+    fn employees_of_another_company_are_listed_by_domain() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let (database, spool) = paths();
+        users(&database, &spool, "create-company mycorp.com")?;
+        let db = SQLiteDatabase::open(&database)?;
+        db.insert_company("othercorp.com")?;
+        db.insert_user("staff@othercorp.com", UserType::Employee)?;
+
+        // Act
+        let employees = users(&database, &spool, "employees othercorp.com")?;
+        let unknown = users(&database, &spool, "employees unknown.com");
+
+        // Assert
+        assert_eq!("othercorp.com", employees.json["company"]["domain_name"]);
+        assert_eq!("1\tstaff@othercorp.com\tEMPLOYEE", employees.text);
+        assert_eq!(
+            UserManagementError::CompanyNotFound.to_string(),
+            unknown.err().unwrap().to_string()
+        );
+
+        fs::remove_file(&database)?;
+        Ok(())
+    }
+
+    #[test]
+    fn changed_emails_are_shown_in_the_history() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let (database, spool) = paths();
+        users(&database, &spool, "create-company mycorp.com")?;
+        users(&database, &spool, "create-user new@mycorp.com")?;
+        let db = SQLiteDatabase::open(&database)?;
+        let mut user = db.get_user_by_id(1)?.unwrap();
+        user.email_confirmed = true;
+        db.save_user(&user)?;
+
+        // Act
+        users(&database, &spool, "change-email 1 new@example.com alice")?;
+        let history = users(&database, &spool, "history 1")?;
+        let employees = users(&database, &spool, "employees")?;
+
+        // Assert
+        assert_eq!("new@mycorp.com", history.json[0]["old_email"]);
+        assert_eq!("new@example.com", history.json[0]["new_email"]);
+        assert_eq!("alice", history.json[0]["actor"]);
+        assert_eq!(0, employees.json["company"]["number_of_employees"]);
+
+        cleanup(&database, &spool)
+    }
+
+    #[test]
+    fn registrations_and_deactivations_are_spooled() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let (database, spool) = paths();
+        users(&database, &spool, "create-company mycorp.com")?;
+
+        // Act
+        users(&database, &spool, "create-user new@mycorp.com")?;
+        users(&database, &spool, "deactivate 1")?;
+
+        // Assert
+        let events = spooled_events(&spool)?;
+        assert_eq!(3, events.len());
+        assert_eq!(
+            DomainEvent::UserRegisteredEvent {
+                user_id: 1,
+                email: "new@mycorp.com".to_owned(),
+                user_type: UserType::Employee,
+            },
+            events[0]
+        );
+        assert!(matches!(
+            events[1],
+            DomainEvent::EmailConfirmationRequestedEvent { user_id: 1, .. }
+        ));
+        assert_eq!(DomainEvent::UserDeactivatedEvent { user_id: 1 }, events[2]);
+
+        cleanup(&database, &spool)
+    }
+
+    #[test]
+    fn legacy_spools_only_get_email_changes() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let (database, spool) = paths();
+        users(&database, &spool, "create-company mycorp.com")?;
+        let db = SQLiteDatabase::open(&database)?;
+        db.insert_user("user@example.com", UserType::Cusotmer)?;
+        let mut user = db.get_user_by_id(1)?.unwrap();
+        user.email_confirmed = true;
+        db.save_user(&user)?;
+
+        // Act
+        users_in(
+            MessageFormat::Legacy,
+            &database,
+            &spool,
+            "create-user new@mycorp.com",
+        )?;
+        users_in(
+            MessageFormat::Legacy,
+            &database,
+            &spool,
+            "change-email 1 other@example.com",
+        )?;
+
+        // Assert
+        let spooled = FileSpoolBus::new(&spool)?.pending()?;
+        assert_eq!(1, spooled.len());
+        assert_eq!(
+            "Type: USER EMAIL CHANGED; Id: 1; NewEmail: other@example.com",
+            spooled[0].message
+        );
+
+        cleanup(&database, &spool)
+    }
+
+    #[test]
+    fn unknown_commands_print_the_usage() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let (database, spool) = paths();
+
+        // Act
+        let result = users(&database, &spool, "frobnicate");
+
+        // Assert
+        assert_eq!(USAGE, result.err().unwrap().to_string());
+
+        fs::remove_file(&database)?;
+        Ok(())
+    }
+}
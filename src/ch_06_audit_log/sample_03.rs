@@ -1,46 +1,89 @@
 use chrono::{DateTime, Utc};
-use std::io::Write;
+use sha2::{Digest, Sha256};
+use std::borrow::Borrow;
+use std::io;
 use std::{
-    fs::{read_to_string, File},
+    fs::{self, read_to_string},
     path::{Path, PathBuf},
 };
-struct AuditManager {
+pub struct AuditManager {
     max_entries_perfile: usize,
 }
 
-struct FileContent {
+pub struct FileContent {
     lines: Vec<String>,
     file_name: String,
 }
 
-struct FileUpdate {
+pub struct FileUpdate {
     path: String,
     content: String,
 }
 
+/// An entry whose hash doesn't follow from the entries before it, so it (or one
+/// before it) was edited, inserted or removed after being written.
+#[derive(Debug, PartialEq, thiserror::Error)]
+#[error("audit entry {line} of {file_name} does not match the entries before it")]
+pub struct TamperedEntry {
+    pub file_name: String,
+    pub line: usize,
+}
+
+const HASH_FIELD: &str = "; hash=";
+
+/// Hashes `entry` together with the hash of the entry written before it.
+fn chain_hash(previous_hash: &str, entry: &str) -> String {
+    Sha256::new()
+        .chain_update(previous_hash)
+        .chain_update("\n")
+        .chain_update(entry)
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Splits a line into its entry and the hash sealing it, if it has one.
+fn split_line(line: &str) -> (&str, Option<&str>) {
+    match line.rsplit_once(HASH_FIELD) {
+        Some((entry, hash)) => (entry, Some(hash)),
+        None => (line, None),
+    }
+}
+
 impl AuditManager {
+    pub fn new(max_entries_perfile: usize) -> Self {
+        Self {
+            max_entries_perfile,
+        }
+    }
+
     pub fn add_record(
         &self,
         files: Vec<FileContent>,
         visitor_name: &str,
         time_of_visit: &DateTime<Utc>,
     ) -> FileUpdate {
-        // TODO: sort
-        let mut sorted = files;
-
-        let new_record = format!("{visitor_name}; {time_of_visit}");
+        self.add_entry(files, format!("{visitor_name}; {time_of_visit}"))
+    }
 
-        if sorted.len() == 0 {
-            return FileUpdate {
-                path: "audit_1.txt".to_owned(),
-                content: new_record,
-            };
-        }
+    /// Appends any single-line `entry` to the log, starting a new file when the
+    /// current one is full.
+    pub fn add_entry(&self, files: Vec<FileContent>, entry: String) -> FileUpdate {
+        let mut sorted = audit_files(files);
 
-        let current_file = sorted.last_mut().unwrap();
+        let (index, current_file) = match sorted.last_mut() {
+            Some((index, current_file)) => (*index, current_file),
+            None => {
+                return FileUpdate {
+                    path: "audit_1.txt".to_owned(),
+                    content: entry,
+                }
+            }
+        };
 
         if current_file.lines.len() < self.max_entries_perfile {
-            current_file.lines.push(new_record);
+            current_file.lines.push(entry);
             let new_content = current_file.lines.join("\n");
             let file_name = current_file.file_name.clone();
             return FileUpdate {
@@ -48,65 +91,143 @@ impl AuditManager {
                 content: new_content,
             };
         } else {
-            let new_index = current_file.lines.len();
+            let new_index = index + 1;
             let new_name = format!("audit_{new_index}.txt");
             return FileUpdate {
                 path: new_name,
-                content: new_record,
+                content: entry,
             };
         }
     }
+
+    /// Like [`Self::add_entry`], but seals `entry` with a hash chained to the
+    /// entry before it, so editing, inserting or removing an earlier entry is
+    /// caught by [`Self::verify`].
+    pub fn add_sealed_entry(&self, files: Vec<FileContent>, entry: String) -> FileUpdate {
+        let previous_hash = audit_files(&files)
+            .iter()
+            .rev()
+            .find_map(|(_, file)| file.lines.last())
+            .and_then(|line| split_line(line).1)
+            .unwrap_or_default()
+            .to_owned();
+        let hash = chain_hash(&previous_hash, &entry);
+
+        self.add_entry(files, format!("{entry}{HASH_FIELD}{hash}"))
+    }
+
+    /// Checks every entry's hash against the entries before it, across all files.
+    /// Only logs written with [`Self::add_sealed_entry`] verify.
+    pub fn verify(&self, files: Vec<FileContent>) -> Result<(), TamperedEntry> {
+        let mut previous_hash = String::new();
+        for (_, file) in audit_files(files) {
+            for (index, line) in file.lines.iter().enumerate() {
+                let (entry, hash) = split_line(line);
+                let expected = chain_hash(&previous_hash, entry);
+                if hash != Some(expected.as_str()) {
+                    return Err(TamperedEntry {
+                        file_name: file.file_name.clone(),
+                        line: index + 1,
+                    });
+                }
+                previous_hash = expected;
+            }
+        }
+
+        Ok(())
+    }
 }
 
-struct Persister {}
+/// The number in `audit_<n>.txt`, so `audit_10.txt` sorts after `audit_9.txt`,
+/// or `None` for files that aren't part of the log.
+fn file_index(file_name: &str) -> Option<usize> {
+    file_name
+        .strip_prefix("audit_")?
+        .strip_suffix(".txt")?
+        .parse()
+        .ok()
+}
+
+/// The log's files in order, paired with their numbers; anything else in the
+/// directory is left out.
+fn audit_files<F: Borrow<FileContent>>(files: impl IntoIterator<Item = F>) -> Vec<(usize, F)> {
+    let mut sorted: Vec<_> = files
+        .into_iter()
+        .filter_map(|file| Some((file_index(&file.borrow().file_name)?, file)))
+        .collect();
+    sorted.sort_by_key(|(index, _)| *index);
+    sorted
+}
+
+pub struct Persister {}
 
 impl Persister {
-    pub fn read_directory(&self, directory_name: &str) -> Vec<FileContent> {
-        Path::new(directory_name)
-            .read_dir()
-            .expect("read_dir call failed")
-            .into_iter()
-            .filter_map(|entry| {
-                if let Ok(entry) = entry {
-                    entry.file_name().to_str().map(|s| s.to_string())
-                } else {
-                    None
-                }
-            })
-            .map(|file_name| {
-                let content = read_to_string(&file_name)
-                    .expect(&format!("failed to read file: {}", file_name));
-
-                FileContent {
-                    file_name,
-                    lines: content.lines().map(|l| l.to_string()).collect::<Vec<_>>(),
-                }
-            })
-            .collect::<Vec<_>>()
+    pub fn read_directory(&self, directory_name: &str) -> io::Result<Vec<FileContent>> {
+        let mut files = vec![];
+
+        for entry in Path::new(directory_name).read_dir()? {
+            let entry = entry?;
+            let Some(file_name) = entry.file_name().to_str().map(|s| s.to_string()) else {
+                continue;
+            };
+            let content = read_to_string(entry.path())?;
+
+            files.push(FileContent {
+                file_name,
+                lines: content.lines().map(|l| l.to_string()).collect::<Vec<_>>(),
+            });
+        }
+
+        Ok(files)
     }
 
-    pub fn apply_update(&self, directory_name: &str, update: FileUpdate) {
+    pub fn apply_update(&self, directory_name: &str, update: FileUpdate) -> io::Result<()> {
         let file_path: PathBuf = [directory_name, &update.path].iter().collect();
-        let mut file =
-            File::open(&file_path).expect(&format!("failed to open file: {:?}", file_path));
-        file.write_all(update.content.as_bytes())
-            .expect(&format!("failed to write file: {:?}", file_path));
+        fs::write(file_path, update.content)
     }
 }
 
-struct ApplicationService {
+pub struct ApplicationService {
     directory_name: String,
     audit_manager: AuditManager,
     persister: Persister,
 }
 
 impl ApplicationService {
-    fn add_record(&self, visitor_name: &str, time_of_visit: &DateTime<Utc>) {
-        let files = self.persister.read_directory(&self.directory_name);
+    pub fn new(directory_name: impl Into<String>, audit_manager: AuditManager) -> Self {
+        Self {
+            directory_name: directory_name.into(),
+            audit_manager,
+            persister: Persister {},
+        }
+    }
+
+    pub fn add_record(&self, visitor_name: &str, time_of_visit: &DateTime<Utc>) -> io::Result<()> {
+        let files = self.persister.read_directory(&self.directory_name)?;
         let update = self
             .audit_manager
             .add_record(files, visitor_name, time_of_visit);
-        self.persister.apply_update(&self.directory_name, update);
+        self.persister.apply_update(&self.directory_name, update)
+    }
+
+    pub fn add_entry(&self, entry: &str) -> io::Result<()> {
+        let files = self.persister.read_directory(&self.directory_name)?;
+        let update = self.audit_manager.add_entry(files, entry.to_owned());
+        self.persister.apply_update(&self.directory_name, update)
+    }
+
+    pub fn add_sealed_entry(&self, entry: &str) -> io::Result<()> {
+        let files = self.persister.read_directory(&self.directory_name)?;
+        let update = self.audit_manager.add_sealed_entry(files, entry.to_owned());
+        self.persister.apply_update(&self.directory_name, update)
+    }
+
+    /// Fails with `InvalidData` if any entry in the log was tampered with.
+    pub fn verify(&self) -> io::Result<()> {
+        let files = self.persister.read_directory(&self.directory_name)?;
+        self.audit_manager
+            .verify(files)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
     }
 }
 
@@ -142,4 +263,123 @@ mod tests {
         assert_eq!("audit_3.txt", update.path);
         assert_eq!("Alice; 2014-11-28 12:00:09 UTC", update.content);
     }
+
+    #[test]
+    fn files_outside_the_log_are_ignored() {
+        let sut = AuditManager::new(3);
+        let files = vec![
+            FileContent {
+                file_name: "notes.txt".to_owned(),
+                lines: vec!["not an audit entry".to_owned()],
+            },
+            FileContent {
+                file_name: "audit_1.txt".to_owned(),
+                lines: vec!["Peter; 2019-04-06T16:30:00".to_owned()],
+            },
+            FileContent {
+                file_name: "audit_1.txt.bak".to_owned(),
+                lines: vec![],
+            },
+        ];
+
+        let update = sut.add_entry(files, "Jane; 2019-04-06T16:40:00".to_owned());
+
+        assert_eq!("audit_1.txt", update.path);
+        assert_eq!(
+            "Peter; 2019-04-06T16:30:00\nJane; 2019-04-06T16:40:00",
+            update.content
+        );
+    }
+
+    #[test]
+    fn sealed_entries_are_chained_to_the_entry_before_them() {
+        let sut = AuditManager::new(1);
+        let first_hash = chain_hash("", "Peter; 1");
+        let files = vec![FileContent {
+            file_name: "audit_1.txt".to_owned(),
+            lines: vec![format!("Peter; 1{HASH_FIELD}{first_hash}")],
+        }];
+
+        let update = sut.add_sealed_entry(files, "Jane; 2".to_owned());
+
+        assert_eq!("audit_2.txt", update.path);
+        assert_eq!(
+            format!("Jane; 2{HASH_FIELD}{}", chain_hash(&first_hash, "Jane; 2")),
+            update.content
+        );
+    }
+
+    fn write_log(sut: &AuditManager, entries: &[&str]) -> Vec<FileContent> {
+        let mut files: Vec<FileContent> = vec![];
+        for entry in entries {
+            let update = sut.add_sealed_entry(
+                files
+                    .iter()
+                    .map(|file| FileContent {
+                        file_name: file.file_name.clone(),
+                        lines: file.lines.clone(),
+                    })
+                    .collect(),
+                entry.to_string(),
+            );
+            files.retain(|file| file.file_name != update.path);
+            files.push(FileContent {
+                file_name: update.path,
+                lines: update.content.lines().map(|l| l.to_string()).collect(),
+            });
+        }
+        files
+    }
+
+    #[test]
+    fn an_untouched_log_verifies() {
+        let sut = AuditManager::new(2);
+        let files = write_log(&sut, &["Peter; 1", "Jane; 2", "Jack; 3"]);
+
+        let result = sut.verify(files);
+
+        assert_eq!(Ok(()), result);
+    }
+
+    #[test]
+    fn an_edited_entry_is_detected() {
+        let sut = AuditManager::new(2);
+        let mut files = write_log(&sut, &["Peter; 1", "Jane; 2", "Jack; 3"]);
+        let first = files
+            .iter_mut()
+            .find(|f| f.file_name == "audit_1.txt")
+            .unwrap();
+        first.lines[1] = first.lines[1].replace("Jane", "Mallory");
+
+        let result = sut.verify(files);
+
+        assert_eq!(
+            Err(TamperedEntry {
+                file_name: "audit_1.txt".to_owned(),
+                line: 2,
+            }),
+            result
+        );
+    }
+
+    #[test]
+    fn a_removed_entry_is_detected() {
+        let sut = AuditManager::new(2);
+        let mut files = write_log(&sut, &["Peter; 1", "Jane; 2", "Jack; 3"]);
+        let first = files
+            .iter_mut()
+            .find(|f| f.file_name == "audit_1.txt")
+            .unwrap();
+        first.lines.remove(0);
+
+        let result = sut.verify(files);
+
+        assert_eq!(
+            Err(TamperedEntry {
+                file_name: "audit_1.txt".to_owned(),
+                line: 1,
+            }),
+            result
+        );
+    }
 }
@@ -23,63 +23,119 @@ struct Company {
     number_of_employees: i64,
 }
 
+#[derive(PartialEq, Debug, thiserror::Error)]
+enum InvariantError {
+    #[error("Email is not yet confirmed")]
+    EmailNotConfirmed,
+    #[error("Email does not contain '@': {0}")]
+    InvalidEmail(String),
+    #[error("Number of employees must not be negative")]
+    NegativeNumberOfEmployees,
+}
+
 impl Company {
-    fn change_number_of_employees(&mut self, delta: i64) {
-        assert!(
-            self.number_of_employees + delta >= 0,
-            "Number of employees must be greater than zero"
-        );
+    fn can_change_number_of_employees(&self, delta: i64) -> Result<(), InvariantError> {
+        if self.number_of_employees + delta < 0 {
+            return Err(InvariantError::NegativeNumberOfEmployees);
+        }
+
+        Ok(())
+    }
+
+    fn change_number_of_employees(&mut self, delta: i64) -> Result<(), InvariantError> {
+        self.can_change_number_of_employees(delta)?;
 
         self.number_of_employees += delta;
+        Ok(())
     }
 
-    fn is_email_corporate(&self, email: &str) -> bool {
-        let email_domain = email
-            .split('@')
-            .nth(1)
-            .expect(&format!("email not contains '@', value: {}", email));
+    fn is_email_corporate(&self, email: &str) -> Result<bool, InvariantError> {
+        let email_domain = match email.split_once('@') {
+            Some((local, domain))
+                if !local.is_empty() && !domain.is_empty() && !domain.contains('@') =>
+            {
+                domain
+            }
+            _ => return Err(InvariantError::InvalidEmail(email.to_owned())),
+        };
 
-        email_domain == self.domain_name
+        Ok(email_domain == self.domain_name)
     }
 }
 
 impl User {
-    pub fn can_change_email(&self) -> Option<&str> {
-        if self.email_confirmed {
-            None
+    /// Returns every rule that prevents changing the email, not just the first.
+    pub fn can_change_email(
+        &self,
+        new_email: &str,
+        company: &Company,
+    ) -> Result<(), Vec<InvariantError>> {
+        let mut violations = vec![];
+
+        if !self.email_confirmed {
+            violations.push(InvariantError::EmailNotConfirmed);
+        }
+
+        match self.type_for_email(new_email, company) {
+            Ok(new_type) => {
+                if let Err(e) =
+                    company.can_change_number_of_employees(self.employee_delta(&new_type))
+                {
+                    violations.push(e);
+                }
+            }
+            Err(e) => violations.push(e),
+        }
+
+        if violations.is_empty() {
+            Ok(())
         } else {
-            Some("Email is not yet confirmed")
+            Err(violations)
         }
     }
 
-    pub fn change_email(&mut self, new_email: &str, company: &mut Company) {
-        assert!(self.email_confirmed, "Email is not yet confirmed");
+    pub fn change_email(
+        &mut self,
+        new_email: &str,
+        company: &mut Company,
+    ) -> Result<(), Vec<InvariantError>> {
+        self.can_change_email(new_email, company)?;
 
         if self.email == new_email {
-            return;
+            return Ok(());
         }
 
-        let new_type = if company.is_email_corporate(new_email) {
-            UserType::Employee
-        } else {
-            UserType::Cusotmer
-        };
-
-        if self.user_type != new_type {
-            let delta = if new_type == UserType::Employee {
-                1
-            } else {
-                -1
-            };
-            company.change_number_of_employees(delta);
-        }
+        let new_type = self
+            .type_for_email(new_email, company)
+            .map_err(|e| vec![e])?;
+        company
+            .change_number_of_employees(self.employee_delta(&new_type))
+            .map_err(|e| vec![e])?;
 
         self.email = new_email.to_owned();
         self.user_type = new_type;
         self.email_changed_events.push(EmailChangeEvent {
             user_id: self.user_id,
             new_email: self.email.to_owned(),
-        })
+        });
+
+        Ok(())
+    }
+
+    fn type_for_email(&self, email: &str, company: &Company) -> Result<UserType, InvariantError> {
+        if company.is_email_corporate(email)? {
+            Ok(UserType::Employee)
+        } else {
+            Ok(UserType::Cusotmer)
+        }
+    }
+
+    fn employee_delta(&self, new_type: &UserType) -> i64 {
+        match (&self.user_type, new_type) {
+            (old, new) if old == new => 0,
+            (_, UserType::Employee) => 1,
+            _ => -1,
+        }
     }
 }
 
@@ -102,13 +158,15 @@ struct UserController<D: Database, M: MessageBus> {
 impl<D: Database, M: MessageBus> UserController<D, M> {
     pub fn change_email(&self, user_id: i64, new_email: &str) -> String {
         let mut user = self.database.get_user_by_id(user_id);
-        if let Some(error) = user.can_change_email() {
-            return error.to_string();
-        }
-
         let mut company = self.database.get_company();
 
-        user.change_email(new_email, &mut company);
+        if let Err(violations) = user.change_email(new_email, &mut company) {
+            return violations
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join("; ");
+        }
 
         self.database.save_company(&company);
         self.database.save_user(&user);
@@ -139,8 +197,9 @@ mod tests {
             user_type: UserType::Employee,
         };
 
-        sut.change_email("new@example.com", &mut company);
+        let result = sut.change_email("new@example.com", &mut company);
 
+        assert_eq!(result, Ok(()));
         assert_eq!(company.number_of_employees, 0);
         assert_eq!(sut.email, "new@example.com");
         assert_eq!(sut.user_type, UserType::Cusotmer);
@@ -152,4 +211,47 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn every_violated_rule_is_reported() {
+        let mut company = Company {
+            domain_name: "mycorp.com".to_owned(),
+            number_of_employees: 0,
+        };
+        let mut sut = User {
+            email: "user@mycorp.com".to_owned(),
+            email_confirmed: false,
+            email_changed_events: vec![],
+            user_id: 1,
+            user_type: UserType::Employee,
+        };
+
+        let result = sut.change_email("new@example.com", &mut company);
+
+        assert_eq!(
+            result,
+            Err(vec![
+                InvariantError::EmailNotConfirmed,
+                InvariantError::NegativeNumberOfEmployees
+            ])
+        );
+        assert_eq!(company.number_of_employees, 0);
+        assert_eq!(sut.email, "user@mycorp.com");
+        assert!(sut.email_changed_events.is_empty());
+    }
+
+    #[test]
+    fn email_with_more_than_one_at_sign_is_invalid() {
+        let company = Company {
+            domain_name: "mycorp.com".to_owned(),
+            number_of_employees: 1,
+        };
+
+        let result = company.is_email_corporate("a@b@mycorp.com");
+
+        assert_eq!(
+            result,
+            Err(InvariantError::InvalidEmail("a@b@mycorp.com".to_owned()))
+        );
+    }
 }
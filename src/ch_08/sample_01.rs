@@ -8,36 +8,38 @@ struct SQLiteDatabase {
 }
 
 impl Database for SQLiteDatabase {
-    type Error = rusqlite::Error;
+    type Error = DatabaseError;
     fn get_user_by_id(&self, user_id: i64) -> Result<Option<User>, Self::Error> {
         let mut stmt = self
             .conn
             .prepare("SELECT id, email, email_confirmed, user_type FROM user WHERE id = ?1")?;
         let user = stmt
             .query_map([user_id], |row| {
-                let user_type: String = row.get(3)?;
                 Ok(User {
                     user_id: row.get(0)?,
                     email: row.get(1)?,
                     email_confirmed: row.get(2)?,
                     email_changed_events: vec![],
-                    user_type: UserType::from(user_type),
+                    user_type: row.get(3)?,
                 })
             })?
             .next()
-            .transpose();
+            .transpose()?;
 
-        user
+        Ok(user)
     }
 
     fn get_company(&self) -> Result<Option<Company>, Self::Error> {
-        let mut stmt = self.conn.prepare("SELECT * from company limit 1")?;
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id, domain, number_of_employees, version FROM company LIMIT 1")?;
         let company = stmt
             .query_map([], |row| {
                 Ok(Company {
                     id: row.get(0)?,
                     domain_name: row.get(1)?,
                     number_of_employees: row.get(2)?,
+                    version: row.get(3)?,
                 })
             })?
             .next()
@@ -46,7 +48,27 @@ impl Database for SQLiteDatabase {
         Ok(company)
     }
 
-    fn save_company(&self, company: &Company) {}
+    fn save_company(&self, company: &Company) -> Result<(), Self::Error> {
+        let updated = self.conn.execute(
+            "UPDATE company SET domain = ?1, number_of_employees = ?2, version = version + 1
+             WHERE id = ?3 AND version = ?4",
+            (
+                &company.domain_name,
+                company.number_of_employees,
+                company.id,
+                company.version,
+            ),
+        )?;
+
+        if updated == 0 {
+            return Err(DatabaseError::ConcurrencyConflict {
+                company_id: company.id,
+                expected_version: company.version,
+            });
+        }
+
+        Ok(())
+    }
 
     fn save_user(&self, user: &User) -> Result<(), Self::Error> {
         self.conn.execute(
@@ -107,6 +129,59 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn changing_email_cannot_drop_employee_count_below_zero() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let mut db = get_db();
+        let user = create_user(&mut db.conn, "user@my_corp", UserType::Employee)?;
+
+        let mut message_bus_mock = MockMessageBus::new();
+        message_bus_mock
+            .expect_send_email_changed_message()
+            .times(0);
+        let sut = UserController::new(db, message_bus_mock);
+
+        // Act
+        let result = sut.change_email(user.user_id, "new@example.com");
+
+        // Assert
+        assert!(matches!(
+            result,
+            Err(UserManagementError::EmployeeCountUnderflow {
+                number_of_employees: 0,
+                delta: -1,
+                ..
+            })
+        ));
+        let user_from_db = sut.database.get_user_by_id(user.user_id)?.unwrap();
+        assert_eq!("user@my_corp", user_from_db.email);
+
+        Ok(())
+    }
+
+    #[test]
+    fn bus_failures_are_reported_as_messaging_errors() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let mut db = get_db();
+        let user = create_user(&mut db.conn, "user@gmail.com", UserType::Cusotmer)?;
+
+        let mut message_bus_mock = MockMessageBus::new();
+        message_bus_mock
+            .expect_send_email_changed_message()
+            .with(eq(user.user_id), eq("user@my_corp"))
+            .times(1)
+            .return_once(|_, _| Err("bus is down".into()));
+        let sut = UserController::new(db, message_bus_mock);
+
+        // Act
+        let result = sut.change_email(user.user_id, "user@my_corp");
+
+        // Assert
+        assert!(matches!(result, Err(UserManagementError::Messaging(_))));
+
+        Ok(())
+    }
+
     #[test]
     #[ignore]
     fn changing_email_from_corporate_to_non_corporate() -> Result<(), Box<dyn error::Error>> {
@@ -120,7 +195,7 @@ mod test {
             .expect_send_email_changed_message()
             .with(eq(user.user_id), eq("new@example.com"))
             .times(1)
-            .return_once(|_, _| {});
+            .return_once(|_, _| Ok(()));
 
         let sut = UserController::new(db, message_bus_mock);
 
@@ -1,30 +1,12 @@
 #[cfg(test)]
 pub mod test_helper {
     use crate::ch_08::types::{Company, User, UserType};
+    use crate::migrations::migrate;
     use rusqlite::{Connection, Result};
 
     pub fn create_db() -> Result<Connection> {
-        let conn = Connection::open_in_memory()?;
-
-        conn.execute(
-            "CREATE TABLE user (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                email TEXT NOT NULL,
-                email_confirmed INT NOT NULL DEFAULT TRUE,
-                user_type TEXT collate BINARY NOT NULL,
-                CHECK (user_type = 'CUSTOMER' OR user_type = 'EMPLOYEE')
-            )",
-            (),
-        )?;
-
-        conn.execute(
-            "CREATE TABLE company (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                domain TEXT NOT NULL,
-                number_of_employees INTEGER NOT NULl
-            )",
-            (),
-        )?;
+        let mut conn = Connection::open_in_memory()?;
+        migrate(&mut conn)?;
 
         println!("{}", rusqlite::version());
 
@@ -66,6 +48,7 @@ pub mod test_helper {
             id: 0,
             domain_name: domain.into(),
             number_of_employees,
+            version: 0,
         };
 
         let tx = conn.transaction()?;
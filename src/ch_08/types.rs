@@ -1,4 +1,6 @@
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ValueRef};
 use std::fmt::Debug;
+use std::str::FromStr;
 
 #[derive(Debug)]
 pub struct User {
@@ -14,12 +16,16 @@ impl User {
         self.email_confirmed
     }
 
-    pub fn change_email(&mut self, new_email: &str, company: &mut Company) {
+    pub fn change_email(
+        &mut self,
+        new_email: &str,
+        company: &mut Company,
+    ) -> Result<(), UserManagementError> {
         if self.email == new_email {
-            return;
+            return Ok(());
         }
 
-        let new_type = if company.is_email_corporate(new_email) {
+        let new_type = if company.is_email_corporate(new_email)? {
             UserType::Employee
         } else {
             UserType::Cusotmer
@@ -31,11 +37,17 @@ impl User {
             } else {
                 -1
             };
-            company.change_number_of_employees(delta);
+            company.change_number_of_employees(delta)?;
         }
 
         self.email = new_email.to_owned();
         self.user_type = new_type;
+        self.email_changed_events.push(EmailChangeEvent {
+            user_id: self.user_id,
+            new_email: self.email.clone(),
+        });
+
+        Ok(())
     }
 }
 
@@ -53,38 +65,71 @@ pub enum UserType {
     Employee,
 }
 
-impl From<String> for UserType {
-    fn from(v: String) -> Self {
-        match v.as_str() {
-            "CUSTOMER" => Self::Cusotmer,
-            _ => Self::Employee,
+#[derive(Debug, PartialEq, thiserror::Error)]
+#[error("unknown user type: {0:?}")]
+pub struct ParseUserTypeError(pub String);
+
+impl FromStr for UserType {
+    type Err = ParseUserTypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "CUSTOMER" => Ok(Self::Cusotmer),
+            "EMPLOYEE" => Ok(Self::Employee),
+            other => Err(ParseUserTypeError(other.to_owned())),
         }
     }
 }
 
+impl TryFrom<String> for UserType {
+    type Error = ParseUserTypeError;
+
+    fn try_from(v: String) -> Result<Self, Self::Error> {
+        v.parse()
+    }
+}
+
+impl FromSql for UserType {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
 pub struct Company {
     pub id: i64,
     pub domain_name: String,
     pub number_of_employees: i64,
+    pub version: i64,
 }
 
 impl Company {
-    fn change_number_of_employees(&mut self, delta: i64) {
-        assert!(
-            self.number_of_employees + delta >= 0,
-            "Number of employees must be greater than zero"
-        );
+    fn change_number_of_employees(&mut self, delta: i64) -> Result<(), UserManagementError> {
+        if self.number_of_employees + delta < 0 {
+            return Err(UserManagementError::EmployeeCountUnderflow {
+                company_id: self.id,
+                number_of_employees: self.number_of_employees,
+                delta,
+            });
+        }
 
         self.number_of_employees += delta;
+        Ok(())
     }
 
-    fn is_email_corporate(&self, email: &str) -> bool {
-        let email_domain = email
-            .split('@')
-            .nth(1)
-            .expect(&format!("email not contains '@', value: {}", email));
+    fn is_email_corporate(&self, email: &str) -> Result<bool, UserManagementError> {
+        let email_domain = match email.split_once('@') {
+            Some((local, domain))
+                if !local.is_empty() && !domain.is_empty() && !domain.contains('@') =>
+            {
+                domain
+            }
+            _ => return Err(UserManagementError::InvalidEmail(email.to_owned())),
+        };
 
-        email_domain == self.domain_name
+        Ok(email_domain == self.domain_name)
     }
 }
 
@@ -92,13 +137,72 @@ pub trait Database {
     type Error: std::error::Error + Send + Sync + 'static;
     fn get_user_by_id(&self, user_id: i64) -> Result<Option<User>, Self::Error>;
     fn get_company(&self) -> Result<Option<Company>, Self::Error>;
-    fn save_company(&self, company: &Company);
+    /// Persists `company`, failing with a conflict when the stored row has been
+    /// updated since `company` was loaded.
+    fn save_company(&self, company: &Company) -> Result<(), Self::Error>;
     fn save_user(&self, user: &User) -> Result<(), Self::Error>;
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum DatabaseError {
+    #[error(
+        "company {company_id} was modified concurrently (expected version {expected_version})"
+    )]
+    ConcurrencyConflict {
+        company_id: i64,
+        expected_version: i64,
+    },
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum UserManagementError {
+    #[error("user {0} not found")]
+    UserNotFound(i64),
+    #[error("company not found")]
+    CompanyNotFound,
+    #[error("email of user {0} is not confirmed")]
+    EmailNotConfirmed(i64),
+    #[error("invalid email: {0}")]
+    InvalidEmail(String),
+    #[error("company {company_id} cannot go from {number_of_employees} employees by {delta}")]
+    EmployeeCountUnderflow {
+        company_id: i64,
+        number_of_employees: i64,
+        delta: i64,
+    },
+    #[error("storage failure")]
+    Storage(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("messaging failure")]
+    Messaging(#[source] Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl From<DatabaseError> for UserManagementError {
+    fn from(e: DatabaseError) -> Self {
+        Self::Storage(Box::new(e))
+    }
+}
+
+impl From<BusError> for UserManagementError {
+    fn from(e: BusError) -> Self {
+        Self::Messaging(Box::new(e))
+    }
+}
+
 #[mockall::automock]
 pub trait MessageBus {
-    fn send_email_changed_message(&self, user_id: i64, new_email: &str);
+    fn send_email_changed_message(&self, user_id: i64, new_email: &str) -> Result<(), BusError>;
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct BusError(#[source] Box<dyn std::error::Error + Send + Sync>);
+
+impl From<&str> for BusError {
+    fn from(message: &str) -> Self {
+        Self(message.into())
+    }
 }
 
 #[derive(derive_more::Constructor)]
@@ -107,30 +211,33 @@ pub struct UserController<D: Database, M: MessageBus> {
     pub message_bus: M,
 }
 
-impl<D: Database, M: MessageBus> UserController<D, M> {
-    pub fn change_email(&self, user_id: i64, new_email: &str) -> anyhow::Result<()> {
-        let mut user = match self.database.get_user_by_id(user_id) {
-            Ok(result) => match result {
-                Some(user) => user,
-                None => return Err(anyhow::anyhow!("err")),
-            },
-            Err(e) => return Err(e.into()),
-        };
+impl<D: Database, M: MessageBus> UserController<D, M>
+where
+    UserManagementError: From<D::Error>,
+{
+    pub fn change_email(&self, user_id: i64, new_email: &str) -> Result<(), UserManagementError> {
+        let mut user = self
+            .database
+            .get_user_by_id(user_id)?
+            .ok_or(UserManagementError::UserNotFound(user_id))?;
 
         if !user.can_change_email() {
-            return Err(anyhow::anyhow!("Cannot change email"));
+            return Err(UserManagementError::EmailNotConfirmed(user_id));
         }
 
-        let mut company = self.database.get_company()?.unwrap();
+        let mut company = self
+            .database
+            .get_company()?
+            .ok_or(UserManagementError::CompanyNotFound)?;
 
-        user.change_email(new_email, &mut company);
+        user.change_email(new_email, &mut company)?;
 
-        self.database.save_company(&company);
+        self.database.save_company(&company)?;
         self.database.save_user(&user)?;
-        user.email_changed_events.iter().for_each(|ev| {
+        for ev in &user.email_changed_events {
             self.message_bus
-                .send_email_changed_message(ev.user_id, &ev.new_email);
-        });
+                .send_email_changed_message(ev.user_id, &ev.new_email)?;
+        }
 
         Ok(())
     }
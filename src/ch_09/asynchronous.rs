@@ -0,0 +1,346 @@
+//! Async counterparts of `Database` and `UserController`.
+//!
+//! The async database wraps a synchronous one and runs its calls on tokio's
+//! blocking pool, so SQLite never blocks the runtime. The sync API is unchanged,
+//! and events, with the bus and domain logger they reach, still go through the
+//! sync `EventDispatcher` on the blocking pool.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::task;
+
+use super::email_history::SYSTEM_ACTOR;
+use super::outbox::OutboxRelay;
+use super::types::{
+    change_email_in, confirm_email_in, Company, Database, EventDispatcher, OutboxMessage, User,
+    UserManagementError,
+};
+
+#[async_trait]
+pub trait AsyncDatabase: Send + Sync {
+    /// The synchronous database units of work run against.
+    type Sync: Database;
+
+    async fn get_user_by_id(&self, user_id: i64) -> Result<Option<User>, UserManagementError>;
+    async fn get_company(&self) -> Result<Option<Company>, UserManagementError>;
+    async fn save_company(&self, company: Company) -> Result<(), UserManagementError>;
+    async fn save_user(&self, user: User) -> Result<(), UserManagementError>;
+    async fn get_undelivered_messages(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<OutboxMessage>, UserManagementError>;
+    async fn mark_delivered(&self, message_id: i64) -> Result<(), UserManagementError>;
+
+    /// Runs `work` against the synchronous database on the blocking pool.
+    async fn run<T, F>(&self, work: F) -> Result<T, UserManagementError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Self::Sync) -> Result<T, UserManagementError> + Send + 'static;
+
+    /// Runs `work` as a single unit of work, like `Database::in_transaction`.
+    async fn transaction<T, F>(&self, work: F) -> Result<T, UserManagementError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Self::Sync) -> Result<T, UserManagementError> + Send + 'static;
+}
+
+/// Runs a synchronous `Database` that can be shared between threads, such as
+/// `PooledSQLiteDatabase`, on the blocking pool.
+///
+/// Calls run concurrently, each on its own blocking thread, and nothing is held
+/// between them: a unit of work that panics fails only its own call.
+pub struct BlockingDatabase<D> {
+    database: Arc<D>,
+}
+
+impl<D: Database + Send + Sync + 'static> BlockingDatabase<D>
+where
+    UserManagementError: From<D::Error>,
+{
+    pub fn new(database: D) -> Self {
+        Self {
+            database: Arc::new(database),
+        }
+    }
+}
+
+#[async_trait]
+impl<D: Database + Send + Sync + 'static> AsyncDatabase for BlockingDatabase<D>
+where
+    UserManagementError: From<D::Error>,
+{
+    type Sync = D;
+
+    async fn run<T, F>(&self, work: F) -> Result<T, UserManagementError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&D) -> Result<T, UserManagementError> + Send + 'static,
+    {
+        let database = Arc::clone(&self.database);
+
+        task::spawn_blocking(move || work(&database))
+            .await
+            .map_err(|e| UserManagementError::Storage(Box::new(e)))?
+    }
+
+    async fn get_user_by_id(&self, user_id: i64) -> Result<Option<User>, UserManagementError> {
+        self.run(move |database| Ok(database.get_user_by_id(user_id)?))
+            .await
+    }
+
+    async fn get_company(&self) -> Result<Option<Company>, UserManagementError> {
+        self.run(|database| Ok(database.get_company()?)).await
+    }
+
+    async fn save_company(&self, company: Company) -> Result<(), UserManagementError> {
+        self.run(move |database| Ok(database.save_company(&company)?))
+            .await
+    }
+
+    async fn save_user(&self, user: User) -> Result<(), UserManagementError> {
+        self.run(move |database| Ok(database.save_user(&user)?))
+            .await
+    }
+
+    async fn get_undelivered_messages(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<OutboxMessage>, UserManagementError> {
+        self.run(move |database| Ok(database.get_undelivered_messages(limit)?))
+            .await
+    }
+
+    async fn mark_delivered(&self, message_id: i64) -> Result<(), UserManagementError> {
+        self.run(move |database| Ok(database.mark_delivered(message_id)?))
+            .await
+    }
+
+    async fn transaction<T, F>(&self, work: F) -> Result<T, UserManagementError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&D) -> Result<T, UserManagementError> + Send + 'static,
+    {
+        self.run(move |database| database.in_transaction(work))
+            .await
+    }
+}
+
+/// The async `UserController`. Events are relayed by the same `OutboxRelay` and
+/// `EventDispatcher` as the sync one, on the blocking pool, so every subscriber
+/// registered with the dispatcher hears about changes made through either.
+pub struct AsyncUserController<D: AsyncDatabase> {
+    pub database: D,
+    event_dispatcher: Arc<EventDispatcher>,
+}
+
+impl<D: AsyncDatabase> AsyncUserController<D>
+where
+    UserManagementError: From<<D::Sync as Database>::Error>,
+{
+    pub fn new(database: D, event_dispatcher: EventDispatcher) -> Self {
+        Self {
+            database,
+            event_dispatcher: Arc::new(event_dispatcher),
+        }
+    }
+
+    pub async fn change_email(
+        &self,
+        user_id: i64,
+        new_email: &str,
+    ) -> Result<(), UserManagementError> {
+        self.change_email_as(user_id, new_email, SYSTEM_ACTOR).await
+    }
+
+    pub async fn change_email_as(
+        &self,
+        user_id: i64,
+        new_email: &str,
+        actor: &str,
+    ) -> Result<(), UserManagementError> {
+        let new_email = new_email.to_owned();
+        let actor = actor.to_owned();
+        self.database
+            .transaction(move |database| change_email_in(database, user_id, &new_email, &actor))
+            .await?;
+
+        self.relay_pending().await;
+
+        Ok(())
+    }
+
+    /// Confirms the email of the user `token` was issued to. Each token works once.
+    pub async fn confirm_email(
+        &self,
+        token: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), UserManagementError> {
+        let token = token.to_owned();
+        self.database
+            .transaction(move |database| confirm_email_in(database, &token, now))
+            .await?;
+
+        self.relay_pending().await;
+
+        Ok(())
+    }
+
+    /// Like `UserController`, sends what the use case just committed and only logs
+    /// a failure: the events stay in the outbox for the next relay. It runs outside
+    /// any transaction, so no connection is held while the bus backs off.
+    async fn relay_pending(&self) {
+        let event_dispatcher = Arc::clone(&self.event_dispatcher);
+        let result = self
+            .database
+            .run(move |database| Ok(OutboxRelay::new(database, &event_dispatcher).relay_pending()?))
+            .await;
+
+        if let Err(e) = result {
+            tracing::warn!(error = %e, "failed to relay the outbox");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mockall::predicate::eq;
+    use std::error;
+
+    use crate::ch_09::bus::channel::ChannelBus;
+    use crate::ch_09::bus_message::MessageEnvelope;
+    use crate::ch_09::pooled_database::PooledSQLiteDatabase;
+    use crate::ch_09::subscribers::{EventHandler, EventKind, HandlerError, SubscriberRegistry};
+    use crate::ch_09::test_helper::test_helper::{create_pooled_db, insert_confirmed_user};
+    use crate::ch_09::types::{DomainEvent, MessageBus, MockDomainLogger, UserType};
+
+    use super::*;
+
+    fn get_db() -> Result<PooledSQLiteDatabase, Box<dyn error::Error>> {
+        let db = create_pooled_db()?;
+        insert_confirmed_user(&db, "user@mycorp.com", UserType::Employee)?;
+        let company_id = db.insert_company("mycorp.com")?;
+        db.adjust_employee_count(company_id, 1)?;
+
+        Ok(db)
+    }
+
+    #[tokio::test]
+    async fn changing_email_from_corporate_to_non_corporate() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let bus = ChannelBus::new();
+        let messages = bus.subscribe();
+        let mut domain_logger_mock = MockDomainLogger::new();
+        domain_logger_mock
+            .expect_user_type_has_changed()
+            .with(eq(1), eq(UserType::Employee), eq(UserType::Cusotmer))
+            .times(1)
+            .returning(|_, _, _| {});
+        let sut = AsyncUserController::new(
+            BlockingDatabase::new(get_db()?),
+            EventDispatcher::new(MessageBus::new(bus), domain_logger_mock),
+        );
+
+        // Act
+        sut.change_email(1, "new@example.com").await?;
+
+        // Assert
+        let user = sut.database.get_user_by_id(1).await?.unwrap();
+        assert_eq!("new@example.com", user.email);
+        assert_eq!(UserType::Cusotmer, user.user_type);
+        assert_eq!(
+            0,
+            sut.database
+                .get_company()
+                .await?
+                .unwrap()
+                .number_of_employees
+        );
+        assert_eq!(
+            vec!["Type: USER EMAIL CHANGED; Id: 1; NewEmail: new@example.com"],
+            messages.try_iter().collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_change_is_reported_and_sends_nothing() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let bus = ChannelBus::new();
+        let messages = bus.subscribe();
+        let sut = AsyncUserController::new(
+            BlockingDatabase::new(get_db()?),
+            EventDispatcher::new(MessageBus::new(bus), MockDomainLogger::new()),
+        );
+
+        // Act
+        let result = sut.change_email(42, "new@example.com").await;
+
+        // Assert
+        assert!(matches!(result, Err(UserManagementError::UserNotFound(42))));
+        assert_eq!(0, messages.try_iter().count());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn a_panicking_unit_of_work_fails_only_its_own_call() -> Result<(), Box<dyn error::Error>>
+    {
+        // Arrange
+        let sut = BlockingDatabase::new(get_db()?);
+
+        // Act
+        let result = sut
+            .transaction(|database| -> Result<(), UserManagementError> {
+                database.insert_company("othercorp.com")?;
+                panic!("unit of work failed");
+            })
+            .await;
+
+        // Assert
+        assert!(matches!(result, Err(UserManagementError::Storage(_))));
+        let companies = sut.run(|database| Ok(database.get_companies()?)).await?;
+        assert_eq!(1, companies.len());
+
+        Ok(())
+    }
+
+    struct Recorder(std::sync::mpsc::Sender<MessageEnvelope>);
+
+    impl EventHandler for Recorder {
+        fn handle(&self, envelope: &MessageEnvelope) -> Result<(), HandlerError> {
+            self.0.send(envelope.clone())?;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn registered_subscribers_hear_about_async_changes() -> Result<(), Box<dyn error::Error>>
+    {
+        // Arrange
+        let (sender, received) = std::sync::mpsc::channel();
+        let mut dispatcher = EventDispatcher::with_subscribers(SubscriberRegistry::new());
+        dispatcher
+            .subscribers_mut()
+            .subscribe("crm", EventKind::EmailChanged, Recorder(sender));
+        let sut = AsyncUserController::new(BlockingDatabase::new(get_db()?), dispatcher);
+
+        // Act
+        sut.change_email(1, "new@example.com").await?;
+
+        // Assert
+        let events: Vec<_> = received.try_iter().map(|envelope| envelope.event).collect();
+        assert_eq!(
+            vec![DomainEvent::EmailChangeEvent {
+                user_id: 1,
+                new_email: "new@example.com".to_owned()
+            }],
+            events
+        );
+        assert!(sut.database.get_undelivered_messages(10).await?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,60 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+
+use crate::ch_09::types::{Bus, BusError};
+
+/// Delivers every message to each subscriber in the same process.
+#[derive(Default)]
+pub struct ChannelBus {
+    subscribers: Mutex<Vec<Sender<String>>>,
+}
+
+impl ChannelBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a receiver for every message sent from now on. Dropping it
+    /// unsubscribes.
+    pub fn subscribe(&self) -> Receiver<String> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+
+        receiver
+    }
+}
+
+impl Bus for ChannelBus {
+    fn send(&self, message: &str) -> Result<(), BusError> {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(message.to_owned()).is_ok());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn messages_reach_every_live_subscriber() {
+        // Arrange
+        let sut = ChannelBus::new();
+        let first = sut.subscribe();
+        let dropped = sut.subscribe();
+        drop(dropped);
+
+        // Act
+        sut.send("one").unwrap();
+        let late = sut.subscribe();
+        sut.send("two").unwrap();
+
+        // Assert
+        assert_eq!(vec!["one", "two"], first.try_iter().collect::<Vec<_>>());
+        assert_eq!(vec!["two"], late.try_iter().collect::<Vec<_>>());
+        assert_eq!(2, sut.subscribers.lock().unwrap().len());
+    }
+}
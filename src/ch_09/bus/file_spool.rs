@@ -0,0 +1,120 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use uuid::Uuid;
+
+use crate::ch_09::types::{Bus, BusError};
+
+const EXTENSION: &str = "msg";
+
+/// Writes each message to its own file in a spool directory for a consumer to
+/// pick up, so messages survive until they have been processed.
+///
+/// Files are written under a temporary name and renamed into place, so a consumer
+/// never sees a partial message. Names sort in the order messages were sent.
+pub struct FileSpoolBus {
+    directory: PathBuf,
+}
+
+/// A message waiting in the spool.
+#[derive(Debug)]
+pub struct SpooledMessage {
+    pub path: PathBuf,
+    pub message: String,
+}
+
+impl FileSpoolBus {
+    /// Spools into `directory`, creating it if needed.
+    pub fn new(directory: impl Into<PathBuf>) -> io::Result<Self> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+
+        Ok(Self { directory })
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub fn write(&self, message: &str) -> io::Result<PathBuf> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(io::Error::other)?
+            .as_nanos();
+        let name = format!("{:032}-{}", nanos, Uuid::new_v4().simple());
+        let temporary = self.directory.join(format!(".{}.tmp", name));
+        let path = self.directory.join(format!("{}.{}", name, EXTENSION));
+
+        fs::write(&temporary, message)?;
+        fs::rename(&temporary, &path)?;
+
+        Ok(path)
+    }
+
+    /// Every spooled message, oldest first.
+    pub fn pending(&self) -> io::Result<Vec<SpooledMessage>> {
+        let mut paths = vec![];
+        for entry in fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == EXTENSION) {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        paths
+            .into_iter()
+            .map(|path| {
+                let message = fs::read_to_string(&path)?;
+                Ok(SpooledMessage { path, message })
+            })
+            .collect()
+    }
+
+    /// Removes a message once the consumer has processed it.
+    pub fn acknowledge(&self, message: &SpooledMessage) -> io::Result<()> {
+        fs::remove_file(&message.path)
+    }
+}
+
+impl Bus for FileSpoolBus {
+    fn send(&self, message: &str) -> Result<(), BusError> {
+        self.write(message)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::error;
+
+    use super::*;
+
+    #[test]
+    fn spooled_messages_are_read_in_order_until_acknowledged() -> Result<(), Box<dyn error::Error>>
+    {
+        // Arrange
+        let directory = env::temp_dir().join(format!("spool-{}", Uuid::new_v4()));
+        let sut = FileSpoolBus::new(&directory)?;
+
+        // Act
+        sut.send("first")?;
+        sut.send("second")?;
+        let pending = sut.pending()?;
+        sut.acknowledge(&pending[0])?;
+
+        // Assert
+        let messages: Vec<_> = pending.iter().map(|m| m.message.as_str()).collect();
+        assert_eq!(vec!["first", "second"], messages);
+        let reopened = FileSpoolBus::new(&directory)?;
+        let remaining: Vec<_> = reopened.pending()?.into_iter().map(|m| m.message).collect();
+        assert_eq!(vec!["second"], remaining);
+
+        fs::remove_dir_all(directory)?;
+        Ok(())
+    }
+}
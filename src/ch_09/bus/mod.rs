@@ -0,0 +1,6 @@
+//! Production implementations of `Bus`.
+
+pub mod channel;
+pub mod file_spool;
+#[cfg(unix)]
+pub mod unix_socket;
@@ -0,0 +1,454 @@
+//! A bus over a Unix domain socket, through a [`Broker`] that forwards every
+//! published message to every connected subscriber.
+//!
+//! Messages are framed as a 4-byte big-endian length followed by UTF-8 bytes. A
+//! client's first frame names its role; subscribers get an acknowledgement once
+//! registered, so nothing published after `subscribe` returns is missed, and
+//! publishers get one for each message once the broker has forwarded it. Frames
+//! longer than [`MAX_FRAME_LENGTH`] are refused on both ends.
+//!
+//! The broker keeps nothing: a message reaches the subscribers connected when it
+//! is published, and no others.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::ch_09::types::{Bus, BusError};
+
+const PUBLISH: &str = "PUBLISH";
+const SUBSCRIBE: &str = "SUBSCRIBE";
+const ACK: &str = "OK";
+
+/// How long a publisher waits for the broker to acknowledge a message.
+const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The longest message a frame may carry, so a peer can't make us allocate an
+/// arbitrary amount of memory by sending a bogus length.
+pub const MAX_FRAME_LENGTH: u32 = 1024 * 1024;
+
+fn write_frame(stream: &mut UnixStream, message: &str) -> io::Result<()> {
+    let length = u32::try_from(message.len())
+        .ok()
+        .filter(|&length| length <= MAX_FRAME_LENGTH)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "message too long"))?;
+    stream.write_all(&length.to_be_bytes())?;
+    stream.write_all(message.as_bytes())
+}
+
+/// Reads the next frame, or `None` if the peer closed the connection.
+fn read_frame(stream: &mut UnixStream) -> io::Result<Option<String>> {
+    let mut length = [0; 4];
+    match stream.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let length = u32::from_be_bytes(length);
+    if length > MAX_FRAME_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "frame of {} bytes is longer than {}",
+                length, MAX_FRAME_LENGTH
+            ),
+        ));
+    }
+
+    let mut message = vec![0; length as usize];
+    stream.read_exact(&mut message)?;
+
+    String::from_utf8(message)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Publishes to the broker at `path`, connecting on first use and reconnecting
+/// once if the connection has dropped.
+///
+/// A message counts as sent once the broker acknowledges having forwarded it. If
+/// the acknowledgement is lost the message is published again, so subscribers may
+/// see it twice.
+pub struct UnixSocketBus {
+    path: PathBuf,
+    stream: Mutex<Option<UnixStream>>,
+}
+
+impl UnixSocketBus {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            stream: Mutex::new(None),
+        }
+    }
+
+    pub fn publish(&self, message: &str) -> io::Result<()> {
+        let mut stream = self.stream.lock().unwrap();
+
+        if let Some(connected) = stream.as_mut() {
+            if Self::deliver(connected, message).is_ok() {
+                return Ok(());
+            }
+        }
+
+        *stream = None;
+        let mut connected = UnixStream::connect(&self.path)?;
+        connected.set_read_timeout(Some(ACK_TIMEOUT))?;
+        write_frame(&mut connected, PUBLISH)?;
+        Self::deliver(&mut connected, message)?;
+        *stream = Some(connected);
+
+        Ok(())
+    }
+
+    fn deliver(stream: &mut UnixStream, message: &str) -> io::Result<()> {
+        write_frame(stream, message)?;
+        match read_frame(stream)?.as_deref() {
+            Some(ACK) => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "broker did not acknowledge the message",
+            )),
+        }
+    }
+}
+
+impl Bus for UnixSocketBus {
+    fn send(&self, message: &str) -> Result<(), BusError> {
+        self.publish(message)?;
+
+        Ok(())
+    }
+}
+
+/// Receives every message published to the broker after subscribing.
+pub struct UnixSocketSubscriber {
+    stream: UnixStream,
+}
+
+impl UnixSocketSubscriber {
+    pub fn subscribe(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut stream = UnixStream::connect(path)?;
+        write_frame(&mut stream, SUBSCRIBE)?;
+        match read_frame(&mut stream)?.as_deref() {
+            Some(ACK) => Ok(Self { stream }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "broker did not acknowledge subscription",
+            )),
+        }
+    }
+
+    /// Blocks until the next message, or returns `None` once the broker has gone.
+    pub fn recv(&mut self) -> io::Result<Option<String>> {
+        read_frame(&mut self.stream)
+    }
+}
+
+/// How a `Broker` treats its subscribers.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct BrokerOptions {
+    /// How long a message may take to reach one subscriber before it is dropped.
+    pub write_timeout: Duration,
+}
+
+impl Default for BrokerOptions {
+    fn default() -> Self {
+        Self {
+            write_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A subscriber's connection, closed once a write to it fails.
+type Subscriber = Arc<Mutex<Option<UnixStream>>>;
+
+/// Forwards messages from publishers to subscribers, one thread per connection.
+pub struct Broker {
+    listener: UnixListener,
+    options: BrokerOptions,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl Broker {
+    /// Listens at `path`, replacing a socket left behind by a broker that is no
+    /// longer running. Anything else at `path` is left alone and fails the bind.
+    pub fn bind(path: impl AsRef<Path>, options: BrokerOptions) -> io::Result<Self> {
+        let path = path.as_ref();
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if !metadata.file_type().is_socket() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ));
+            }
+            Ok(_) if UnixStream::connect(path).is_ok() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("a broker is already listening at {}", path.display()),
+                ));
+            }
+            Ok(_) => fs::remove_file(path)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        Ok(Self {
+            listener: UnixListener::bind(path)?,
+            options,
+            subscribers: Arc::default(),
+        })
+    }
+
+    /// Accepts connections until the listener fails.
+    pub fn run(&self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let subscribers = Arc::clone(&self.subscribers);
+            let options = self.options;
+            thread::spawn(move || {
+                if let Err(e) = Self::handle(stream, &subscribers, options) {
+                    tracing::warn!(error = %e, "bus client disconnected");
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    fn handle(
+        mut stream: UnixStream,
+        subscribers: &Mutex<Vec<Subscriber>>,
+        options: BrokerOptions,
+    ) -> io::Result<()> {
+        match read_frame(&mut stream)?.as_deref() {
+            Some(SUBSCRIBE) => {
+                stream.set_write_timeout(Some(options.write_timeout))?;
+                write_frame(&mut stream, ACK)?;
+                subscribers
+                    .lock()
+                    .unwrap()
+                    .push(Arc::new(Mutex::new(Some(stream))));
+            }
+            Some(PUBLISH) => {
+                while let Some(message) = read_frame(&mut stream)? {
+                    Self::forward(&message, subscribers);
+                    write_frame(&mut stream, ACK)?;
+                }
+            }
+            Some(role) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown role: {}", role),
+                ))
+            }
+            None => {}
+        }
+
+        Ok(())
+    }
+
+    /// Writes `message` to every subscriber, dropping those a write fails or times
+    /// out for. The list is only locked to copy and prune it, so a slow subscriber
+    /// holds up the publishers writing to it and no others.
+    fn forward(message: &str, subscribers: &Mutex<Vec<Subscriber>>) {
+        let current = subscribers.lock().unwrap().clone();
+
+        let failed: Vec<_> = current
+            .into_iter()
+            .filter(|subscriber| {
+                let mut stream = subscriber.lock().unwrap();
+                let written = match stream.as_mut() {
+                    Some(connected) => write_frame(connected, message).is_ok(),
+                    None => false,
+                };
+                if !written {
+                    *stream = None;
+                }
+
+                !written
+            })
+            .collect();
+
+        if !failed.is_empty() {
+            subscribers
+                .lock()
+                .unwrap()
+                .retain(|subscriber| !failed.iter().any(|f| Arc::ptr_eq(f, subscriber)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::error;
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn published_messages_reach_subscribers_through_the_broker() -> Result<(), Box<dyn error::Error>>
+    {
+        // Arrange
+        let path = env::temp_dir().join(format!("bus-{}.sock", Uuid::new_v4().simple()));
+        let broker = Broker::bind(&path, BrokerOptions::default())?;
+        thread::spawn(move || broker.run());
+        let mut first = UnixSocketSubscriber::subscribe(&path)?;
+        let mut second = UnixSocketSubscriber::subscribe(&path)?;
+        let sut = UnixSocketBus::new(&path);
+
+        // Act
+        sut.send("Type: USER EMAIL CHANGED; Id: 1; NewEmail: new@example.com")?;
+        sut.send("{\"schema_version\":1}")?;
+
+        // Assert
+        for subscriber in [&mut first, &mut second] {
+            assert_eq!(
+                Some("Type: USER EMAIL CHANGED; Id: 1; NewEmail: new@example.com".to_owned()),
+                subscriber.recv()?
+            );
+            assert_eq!(
+                Some("{\"schema_version\":1}".to_owned()),
+                subscriber.recv()?
+            );
+        }
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_frames_are_refused() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let (mut writer, mut reader) = UnixStream::pair()?;
+
+        // Act
+        writer.write_all(&u32::MAX.to_be_bytes())?;
+        let result = read_frame(&mut reader);
+
+        // Assert
+        assert_eq!(io::ErrorKind::InvalidData, result.unwrap_err().kind());
+        let too_long = "x".repeat(MAX_FRAME_LENGTH as usize + 1);
+        assert_eq!(
+            io::ErrorKind::InvalidInput,
+            write_frame(&mut writer, &too_long).unwrap_err().kind()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn binding_leaves_files_and_running_brokers_alone() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let file = env::temp_dir().join(format!("bus-{}.sock", Uuid::new_v4().simple()));
+        fs::write(&file, "not a socket")?;
+        let path = env::temp_dir().join(format!("bus-{}.sock", Uuid::new_v4().simple()));
+        let running = Broker::bind(&path, BrokerOptions::default())?;
+        thread::spawn(move || running.run());
+
+        // Act
+        let over_file = Broker::bind(&file, BrokerOptions::default());
+        let over_running = Broker::bind(&path, BrokerOptions::default());
+
+        // Assert
+        assert_eq!(
+            io::ErrorKind::AlreadyExists,
+            over_file.err().unwrap().kind()
+        );
+        assert_eq!("not a socket", fs::read_to_string(&file)?);
+        assert_eq!(io::ErrorKind::AddrInUse, over_running.err().unwrap().kind());
+        UnixSocketSubscriber::subscribe(&path)?;
+
+        fs::remove_file(file)?;
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn a_stale_socket_is_replaced() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let path = env::temp_dir().join(format!("bus-{}.sock", Uuid::new_v4().simple()));
+        drop(Broker::bind(&path, BrokerOptions::default())?);
+
+        // Act
+        let broker = Broker::bind(&path, BrokerOptions::default())?;
+        thread::spawn(move || broker.run());
+
+        // Assert
+        UnixSocketSubscriber::subscribe(&path)?;
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn a_stalled_subscriber_is_dropped_without_holding_up_others(
+    ) -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let path = env::temp_dir().join(format!("bus-{}.sock", Uuid::new_v4().simple()));
+        let broker = Broker::bind(
+            &path,
+            BrokerOptions {
+                write_timeout: Duration::from_millis(100),
+            },
+        )?;
+        let subscribers = Arc::clone(&broker.subscribers);
+        thread::spawn(move || broker.run());
+        let _stalled = UnixSocketSubscriber::subscribe(&path)?;
+        let mut live = UnixSocketSubscriber::subscribe(&path)?;
+        let received = thread::spawn(move || -> io::Result<usize> {
+            let mut count = 0;
+            while count < 8 && live.recv()?.is_some() {
+                count += 1;
+            }
+            Ok(count)
+        });
+        let sut = UnixSocketBus::new(&path);
+
+        // Act
+        // Far more than a socket buffers, so writes to the stalled one time out.
+        let message = "x".repeat(512 * 1024);
+        for _ in 0..8 {
+            sut.send(&message)?;
+        }
+
+        // Assert
+        assert_eq!(8, received.join().unwrap()?);
+        assert_eq!(1, subscribers.lock().unwrap().len());
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn unacknowledged_messages_are_not_reported_sent() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let path = env::temp_dir().join(format!("bus-{}.sock", Uuid::new_v4().simple()));
+        let listener = UnixListener::bind(&path)?;
+        // A broker that reads the message and goes away without acknowledging it.
+        thread::spawn(move || -> io::Result<()> {
+            let (mut stream, _) = listener.accept()?;
+            read_frame(&mut stream)?;
+            read_frame(&mut stream)?;
+            Ok(())
+        });
+        let sut = UnixSocketBus::new(&path);
+
+        // Act
+        let result = sut.send("Type: USER EMAIL CONFIRMED; Id: 1");
+
+        // Assert
+        assert!(result.is_err());
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+}
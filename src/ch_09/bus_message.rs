@@ -0,0 +1,270 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::types::DomainEvent;
+
+/// Version of the envelope and payload layout. Bump it on any breaking change so
+/// consumers can tell which layout they are reading.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// How `MessageBus` encodes the events it sends.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum MessageFormat {
+    /// `Type: ...; Id: ...` text understood by existing subscribers. Only events
+    /// that had a text form before envelopes existed are sent in this format.
+    Legacy,
+    /// JSON `MessageEnvelope`, sent for every event.
+    Json,
+}
+
+impl MessageFormat {
+    /// The message to send for `envelope`, or `None` if this format skips it.
+    pub fn encode(self, envelope: &MessageEnvelope) -> Option<String> {
+        match self {
+            MessageFormat::Json => Some(envelope.to_json()),
+            MessageFormat::Legacy => legacy_message(&envelope.event),
+        }
+    }
+}
+
+/// A domain event together with the metadata consumers need to order and
+/// de-duplicate it. `event` is flattened, so the JSON carries `event_type` and
+/// `payload` next to the envelope fields.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct MessageEnvelope {
+    pub schema_version: u32,
+    pub event_id: Uuid,
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: DomainEvent,
+}
+
+/// A message as read off the bus.
+#[derive(Debug, PartialEq)]
+pub enum BusMessage {
+    Envelope(MessageEnvelope),
+    Legacy(DomainEvent),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MessageParseError {
+    #[error("malformed message: {0}")]
+    Malformed(String),
+    #[error("unsupported schema version {0} (newest supported is {SCHEMA_VERSION})")]
+    UnsupportedSchemaVersion(u32),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+impl MessageEnvelope {
+    pub fn new(event: DomainEvent, event_id: Uuid, timestamp: DateTime<Utc>) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            event_id,
+            timestamp,
+            event,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("envelope always serializes")
+    }
+}
+
+/// Formats `event` the way subscribers predating envelopes expect. Only email
+/// changes had a text form back then; every other event is `None`.
+pub fn legacy_message(event: &DomainEvent) -> Option<String> {
+    match event {
+        DomainEvent::EmailChangeEvent { user_id, new_email } => Some(format!(
+            "Type: USER EMAIL CHANGED; Id: {}; NewEmail: {}",
+            user_id, new_email
+        )),
+        DomainEvent::UserTypeChangeEvent { .. }
+        | DomainEvent::EmailConfirmationRequestedEvent { .. }
+        | DomainEvent::EmailConfirmedEvent { .. }
+        | DomainEvent::UserRegisteredEvent { .. }
+        | DomainEvent::UserDeactivatedEvent { .. }
+        | DomainEvent::UserReactivatedEvent { .. }
+        | DomainEvent::UserDeletedEvent { .. }
+        | DomainEvent::EmployeeCountCorrectedEvent { .. } => None,
+    }
+}
+
+/// Decodes a message in either format.
+pub fn parse_message(message: &str) -> Result<BusMessage, MessageParseError> {
+    if message.trim_start().starts_with('{') {
+        let version: SchemaVersionOnly = serde_json::from_str(message)?;
+        if version.schema_version > SCHEMA_VERSION {
+            return Err(MessageParseError::UnsupportedSchemaVersion(
+                version.schema_version,
+            ));
+        }
+
+        return Ok(BusMessage::Envelope(serde_json::from_str(message)?));
+    }
+
+    parse_legacy(message).map(BusMessage::Legacy)
+}
+
+#[derive(Deserialize)]
+struct SchemaVersionOnly {
+    schema_version: u32,
+}
+
+fn parse_legacy(message: &str) -> Result<DomainEvent, MessageParseError> {
+    let malformed = || MessageParseError::Malformed(message.to_owned());
+    let fields = message
+        .split("; ")
+        .map(|field| field.split_once(": ").ok_or_else(malformed))
+        .collect::<Result<Vec<_>, _>>()?;
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| *value)
+            .ok_or_else(malformed)
+    };
+    let user_id = field("Id")?.parse().map_err(|_| malformed())?;
+
+    match field("Type")? {
+        "USER EMAIL CHANGED" => Ok(DomainEvent::EmailChangeEvent {
+            user_id,
+            new_email: field("NewEmail")?.to_owned(),
+        }),
+        _ => Err(malformed()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ch_09::types::UserType;
+
+    fn envelope(event: DomainEvent) -> MessageEnvelope {
+        MessageEnvelope::new(
+            event,
+            Uuid::parse_str("67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap(),
+            "2023-02-18T12:00:00Z".parse().unwrap(),
+        )
+    }
+
+    #[test]
+    fn envelope_json_layout() {
+        let sut = envelope(DomainEvent::UserTypeChangeEvent {
+            user_id: 1,
+            old_type: UserType::Employee,
+            new_type: UserType::Cusotmer,
+        });
+
+        let json: serde_json::Value = serde_json::from_str(&sut.to_json()).unwrap();
+
+        assert_eq!(
+            serde_json::json!({
+                "schema_version": 1,
+                "event_id": "67e55044-10b1-426f-9247-bb680e5fe0c8",
+                "timestamp": "2023-02-18T12:00:00Z",
+                "event_type": "USER_TYPE_CHANGED",
+                "payload": {
+                    "user_id": 1,
+                    "old_type": "EMPLOYEE",
+                    "new_type": "CUSTOMER"
+                }
+            }),
+            json
+        );
+    }
+
+    #[test]
+    fn every_event_round_trips_through_json() {
+        let events = vec![
+            DomainEvent::EmailChangeEvent {
+                user_id: 1,
+                new_email: "new@example.com".to_owned(),
+            },
+            DomainEvent::UserTypeChangeEvent {
+                user_id: 1,
+                old_type: UserType::Contractor,
+                new_type: UserType::Admin,
+            },
+            DomainEvent::EmailConfirmationRequestedEvent {
+                user_id: 1,
+                email: "user@example.com".to_owned(),
+                token: "abc".to_owned(),
+            },
+            DomainEvent::EmailConfirmedEvent { user_id: 1 },
+            DomainEvent::UserRegisteredEvent {
+                user_id: 1,
+                email: "user@example.com".to_owned(),
+                user_type: UserType::Employee,
+            },
+            DomainEvent::UserDeactivatedEvent { user_id: 1 },
+            DomainEvent::UserReactivatedEvent { user_id: 1 },
+            DomainEvent::UserDeletedEvent { user_id: 1 },
+            DomainEvent::EmployeeCountCorrectedEvent {
+                company_id: 1,
+                old_count: 2,
+                new_count: 3,
+            },
+        ];
+
+        for event in events {
+            let sut = envelope(event);
+
+            let parsed = parse_message(&sut.to_json()).unwrap();
+
+            assert_eq!(BusMessage::Envelope(sut), parsed);
+        }
+    }
+
+    #[test]
+    fn legacy_messages_are_parsed() {
+        let parsed =
+            parse_message("Type: USER EMAIL CHANGED; Id: 1; NewEmail: new@example.com").unwrap();
+
+        assert_eq!(
+            BusMessage::Legacy(DomainEvent::EmailChangeEvent {
+                user_id: 1,
+                new_email: "new@example.com".to_owned()
+            }),
+            parsed
+        );
+    }
+
+    #[test]
+    fn only_email_changes_have_a_legacy_form() {
+        let confirmed = envelope(DomainEvent::EmailConfirmedEvent { user_id: 1 });
+        let changed = envelope(DomainEvent::EmailChangeEvent {
+            user_id: 1,
+            new_email: "new@example.com".to_owned(),
+        });
+
+        assert_eq!(None, MessageFormat::Legacy.encode(&confirmed));
+        assert_eq!(
+            Some("Type: USER EMAIL CHANGED; Id: 1; NewEmail: new@example.com".to_owned()),
+            MessageFormat::Legacy.encode(&changed)
+        );
+    }
+
+    #[test]
+    fn newer_schema_versions_are_rejected() {
+        let mut json: serde_json::Value = serde_json::from_str(
+            &envelope(DomainEvent::EmailConfirmedEvent { user_id: 1 }).to_json(),
+        )
+        .unwrap();
+        json["schema_version"] = serde_json::json!(SCHEMA_VERSION + 1);
+
+        let result = parse_message(&json.to_string());
+
+        assert!(matches!(
+            result,
+            Err(MessageParseError::UnsupportedSchemaVersion(2))
+        ));
+    }
+
+    #[test]
+    fn garbage_is_rejected() {
+        assert!(parse_message("Type: USER EMAIL CHANGED; Id: one").is_err());
+        assert!(parse_message("hello").is_err());
+    }
+}
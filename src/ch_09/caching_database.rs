@@ -0,0 +1,587 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+
+use super::bus_message::MessageEnvelope;
+use super::dead_letter::DeadLetter;
+use super::email_confirmation::ConfirmationToken;
+use super::email_history::EmailChange;
+use super::subscribers::DispatchError;
+use super::types::*;
+
+/// How much a `CachingDatabase` keeps, and for how long.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CacheOptions {
+    /// Users kept at most; the longest-cached one is evicted to make room.
+    pub capacity: usize,
+    /// How long a cached row is served before it is read again.
+    pub ttl: Duration,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        Self {
+            capacity: 1000,
+            ttl: Duration::from_secs(60),
+        }
+    }
+}
+
+/// How often lookups were served from the cache.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+struct Cached<T> {
+    value: T,
+    cached_at: Instant,
+}
+
+/// Cached rows a transaction has written, dropped once it commits.
+#[derive(Default)]
+struct Invalidations {
+    users: HashSet<i64>,
+    company: bool,
+}
+
+/// Serves `get_user_by_id` and `get_company` from memory in front of any other
+/// `Database`, and forwards everything else.
+///
+/// Saving a user or the company drops its cached copy once the write is
+/// committed, so changes made through this database are seen right away; changes
+/// made elsewhere are seen once the TTL runs out. Inside a transaction the cache
+/// is bypassed, so it never holds rows that may yet be rolled back.
+///
+/// Every drop bumps the row's generation, and a row read from `inner` is only
+/// cached if its generation is unchanged since the read began, so a read racing a
+/// commit can't put back the row the commit replaced.
+pub struct CachingDatabase<D: Database> {
+    inner: D,
+    options: CacheOptions,
+    users: Mutex<HashMap<i64, Cached<User>>>,
+    user_generations: Mutex<HashMap<i64, u64>>,
+    company: Mutex<Option<Cached<Company>>>,
+    company_generation: AtomicU64,
+    transactions: Mutex<HashMap<ThreadId, Invalidations>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<D: Database> CachingDatabase<D> {
+    pub fn new(inner: D, options: CacheOptions) -> Self {
+        Self {
+            inner,
+            options,
+            users: Mutex::new(HashMap::new()),
+            user_generations: Mutex::new(HashMap::new()),
+            company: Mutex::new(None),
+            company_generation: AtomicU64::new(0),
+            transactions: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Drops every cached row.
+    pub fn clear(&self) {
+        self.users.lock().unwrap().clear();
+        self.company.lock().unwrap().take();
+    }
+
+    fn in_transaction_on_this_thread(&self) -> bool {
+        self.transactions
+            .lock()
+            .unwrap()
+            .contains_key(&thread::current().id())
+    }
+
+    /// Drops what `invalidations` lists now, or on commit if the calling thread
+    /// is in a transaction.
+    fn invalidate(&self, invalidations: Invalidations) {
+        if let Some(pending) = self
+            .transactions
+            .lock()
+            .unwrap()
+            .get_mut(&thread::current().id())
+        {
+            pending.users.extend(invalidations.users);
+            pending.company |= invalidations.company;
+            return;
+        }
+
+        // Generations are bumped under the same lock as the cache is checked
+        // against them, so no row read before this can be cached after it.
+        let mut users = self.users.lock().unwrap();
+        let mut generations = self.user_generations.lock().unwrap();
+        for user_id in invalidations.users {
+            users.remove(&user_id);
+            *generations.entry(user_id).or_default() += 1;
+        }
+        if invalidations.company {
+            let mut company = self.company.lock().unwrap();
+            company.take();
+            self.company_generation.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn user_generation(&self, user_id: i64) -> u64 {
+        self.user_generations
+            .lock()
+            .unwrap()
+            .get(&user_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn invalidate_user(&self, user_id: i64) {
+        self.invalidate(Invalidations {
+            users: HashSet::from([user_id]),
+            company: false,
+        });
+    }
+
+    fn invalidate_company(&self) {
+        self.invalidate(Invalidations {
+            users: HashSet::new(),
+            company: true,
+        });
+    }
+
+    fn is_fresh<T>(&self, cached: &Cached<T>) -> bool {
+        cached.cached_at.elapsed() < self.options.ttl
+    }
+
+    fn record(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Caches `user`, read from `inner` while the row was at `generation`, unless
+    /// it has been dropped since.
+    fn cache_user(&self, user: &User, generation: u64) {
+        if self.options.capacity == 0 {
+            return;
+        }
+
+        let mut users = self.users.lock().unwrap();
+        if self.user_generation(user.user_id) != generation {
+            return;
+        }
+        if !users.contains_key(&user.user_id) && users.len() >= self.options.capacity {
+            let oldest = users
+                .iter()
+                .min_by_key(|(_, cached)| cached.cached_at)
+                .map(|(&user_id, _)| user_id);
+            if let Some(user_id) = oldest {
+                users.remove(&user_id);
+            }
+        }
+
+        users.insert(
+            user.user_id,
+            Cached {
+                value: User {
+                    domain_events: vec![],
+                    ..user.clone()
+                },
+                cached_at: Instant::now(),
+            },
+        );
+    }
+
+    /// Caches `company`, read from `inner` at `generation`, unless it has been
+    /// dropped since.
+    fn cache_company(&self, company: &Company, generation: u64) {
+        let mut cached = self.company.lock().unwrap();
+        if self.company_generation.load(Ordering::SeqCst) != generation {
+            return;
+        }
+
+        *cached = Some(Cached {
+            value: company.clone(),
+            cached_at: Instant::now(),
+        });
+    }
+}
+
+impl<D: Database> Database for CachingDatabase<D> {
+    type Error = D::Error;
+
+    fn get_user_by_id(&self, user_id: i64) -> Result<Option<User>, Self::Error> {
+        if self.in_transaction_on_this_thread() {
+            return self.inner.get_user_by_id(user_id);
+        }
+
+        let cached = self
+            .users
+            .lock()
+            .unwrap()
+            .get(&user_id)
+            .filter(|cached| self.is_fresh(cached))
+            .map(|cached| cached.value.clone());
+        self.record(cached.is_some());
+        if cached.is_some() {
+            return Ok(cached);
+        }
+
+        let generation = self.user_generation(user_id);
+        let user = self.inner.get_user_by_id(user_id)?;
+        if let Some(user) = &user {
+            self.cache_user(user, generation);
+        }
+
+        Ok(user)
+    }
+
+    fn get_user_including_inactive(&self, user_id: i64) -> Result<Option<User>, Self::Error> {
+        self.inner.get_user_including_inactive(user_id)
+    }
+
+    fn get_company(&self) -> Result<Option<Company>, Self::Error> {
+        if self.in_transaction_on_this_thread() {
+            return self.inner.get_company();
+        }
+
+        let cached = self
+            .company
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|cached| self.is_fresh(cached))
+            .map(|cached| cached.value.clone());
+        self.record(cached.is_some());
+        if cached.is_some() {
+            return Ok(cached);
+        }
+
+        let generation = self.company_generation.load(Ordering::SeqCst);
+        let company = self.inner.get_company()?;
+        if let Some(company) = &company {
+            self.cache_company(company, generation);
+        }
+
+        Ok(company)
+    }
+
+    fn get_companies(&self) -> Result<Vec<Company>, Self::Error> {
+        self.inner.get_companies()
+    }
+
+    fn insert_company(&self, domain_name: &str) -> Result<i64, Self::Error> {
+        self.inner.insert_company(domain_name)
+    }
+
+    fn get_employees(&self, domain_name: &str) -> Result<Vec<User>, Self::Error> {
+        self.inner.get_employees(domain_name)
+    }
+
+    fn count_employees(&self, domain_name: &str) -> Result<i64, Self::Error> {
+        self.inner.count_employees(domain_name)
+    }
+
+    fn save_company(&self, company: &Company) -> Result<(), Self::Error> {
+        let result = self.inner.save_company(company);
+        self.invalidate_company();
+        result
+    }
+
+    fn adjust_employee_count(&self, company_id: i64, delta: i64) -> Result<(), Self::Error> {
+        let result = self.inner.adjust_employee_count(company_id, delta);
+        self.invalidate_company();
+        result
+    }
+
+    fn insert_user(&self, email: &str, user_type: UserType) -> Result<i64, Self::Error> {
+        self.inner.insert_user(email, user_type)
+    }
+
+    fn save_user_as(&self, user: &User, actor: &str) -> Result<(), Self::Error> {
+        let result = self.inner.save_user_as(user, actor);
+        self.invalidate_user(user.user_id);
+        result
+    }
+
+    fn get_email_history(&self, user_id: i64) -> Result<Vec<EmailChange>, Self::Error> {
+        self.inner.get_email_history(user_id)
+    }
+
+    fn find_email_owner(&self, email: &str, at: DateTime<Utc>) -> Result<Option<i64>, Self::Error> {
+        self.inner.find_email_owner(email, at)
+    }
+
+    fn add_to_outbox(&self, events: &[DomainEvent]) -> Result<(), Self::Error> {
+        self.inner.add_to_outbox(events)
+    }
+
+    fn get_undelivered_messages(&self, limit: usize) -> Result<Vec<OutboxMessage>, Self::Error> {
+        self.inner.get_undelivered_messages(limit)
+    }
+
+    fn mark_delivered(&self, message_id: i64) -> Result<(), Self::Error> {
+        self.inner.mark_delivered(message_id)
+    }
+
+    fn add_dead_letter(
+        &self,
+        envelope: &MessageEnvelope,
+        error: &DispatchError,
+    ) -> Result<(), Self::Error> {
+        self.inner.add_dead_letter(envelope, error)
+    }
+
+    fn get_dead_letters(&self) -> Result<Vec<DeadLetter>, Self::Error> {
+        self.inner.get_dead_letters()
+    }
+
+    fn mark_replayed(&self, dead_letter_id: i64) -> Result<(), Self::Error> {
+        self.inner.mark_replayed(dead_letter_id)
+    }
+
+    fn save_confirmation_token(&self, token: &ConfirmationToken) -> Result<(), Self::Error> {
+        self.inner.save_confirmation_token(token)
+    }
+
+    fn get_confirmation_token(
+        &self,
+        token: &str,
+    ) -> Result<Option<ConfirmationToken>, Self::Error> {
+        self.inner.get_confirmation_token(token)
+    }
+
+    fn begin(&self) -> Result<(), Self::Error> {
+        self.inner.begin()?;
+        self.transactions
+            .lock()
+            .unwrap()
+            .entry(thread::current().id())
+            .or_default();
+
+        Ok(())
+    }
+
+    fn commit(&self) -> Result<(), Self::Error> {
+        let result = self.inner.commit();
+        // Dropped even if the commit failed: the rows are then unchanged, and
+        // reading them again is harmless.
+        let pending = self
+            .transactions
+            .lock()
+            .unwrap()
+            .remove(&thread::current().id());
+        if let Some(pending) = pending {
+            self.invalidate(pending);
+        }
+
+        result
+    }
+
+    fn rollback(&self) -> Result<(), Self::Error> {
+        self.transactions
+            .lock()
+            .unwrap()
+            .remove(&thread::current().id());
+        self.inner.rollback()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error;
+    use std::sync::Arc;
+    use std::{env, fs};
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::ch_09::pooled_database::{PoolOptions, PooledSQLiteDatabase};
+    use crate::ch_09::sqlite_database::SQLiteDatabase;
+    use crate::ch_09::test_helper::test_helper::{create_company, create_db, create_user};
+
+    fn get_db() -> Result<SQLiteDatabase, Box<dyn error::Error>> {
+        let mut db = SQLiteDatabase::new(create_db()?);
+        create_user(&mut db.conn, "user@mycorp.com", UserType::Employee)?;
+        create_user(&mut db.conn, "other@mycorp.com", UserType::Employee)?;
+        create_company(&mut db.conn, "mycorp.com", 2)?;
+
+        Ok(db)
+    }
+
+    #[test]
+    fn saving_drops_the_cached_copy() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let sut = CachingDatabase::new(get_db()?, CacheOptions::default());
+        let mut user = sut.get_user_by_id(1)?.unwrap();
+        let mut company = sut.get_company()?.unwrap();
+
+        // Act
+        user.email = "new@mycorp.com".to_owned();
+        sut.save_user(&user)?;
+        company.number_of_employees = 3;
+        sut.save_company(&company)?;
+        let user_from_db = sut.get_user_by_id(1)?.unwrap();
+        let company_from_db = sut.get_company()?.unwrap();
+        sut.get_user_by_id(1)?;
+
+        // Assert
+        assert_eq!("new@mycorp.com", user_from_db.email);
+        assert_eq!(3, company_from_db.number_of_employees);
+        assert_eq!(CacheStats { hits: 1, misses: 4 }, sut.stats());
+
+        Ok(())
+    }
+
+    #[test]
+    fn expired_rows_are_read_again() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let options = CacheOptions {
+            ttl: Duration::ZERO,
+            ..CacheOptions::default()
+        };
+        let sut = CachingDatabase::new(get_db()?, options);
+
+        // Act
+        sut.get_company()?;
+        sut.inner()
+            .conn
+            .execute("UPDATE company SET number_of_employees = 5", ())?;
+        let company = sut.get_company()?.unwrap();
+
+        // Assert
+        assert_eq!(5, company.number_of_employees);
+        assert_eq!(CacheStats { hits: 0, misses: 2 }, sut.stats());
+
+        Ok(())
+    }
+
+    #[test]
+    fn the_longest_cached_user_is_evicted_when_full() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let options = CacheOptions {
+            capacity: 1,
+            ..CacheOptions::default()
+        };
+        let sut = CachingDatabase::new(get_db()?, options);
+
+        // Act
+        sut.get_user_by_id(1)?;
+        sut.get_user_by_id(2)?;
+        sut.get_user_by_id(2)?;
+        sut.get_user_by_id(1)?;
+
+        // Assert
+        assert_eq!(CacheStats { hits: 1, misses: 3 }, sut.stats());
+
+        Ok(())
+    }
+
+    #[test]
+    fn the_cache_is_bypassed_inside_a_transaction() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let sut = CachingDatabase::new(get_db()?, CacheOptions::default());
+        sut.get_user_by_id(1)?;
+
+        // Act
+        let result = sut.in_transaction(|database| -> Result<(), DatabaseError> {
+            let mut user = database.get_user_by_id(1)?.unwrap();
+            user.email = "new@mycorp.com".to_owned();
+            database.save_user(&user)?;
+            assert_eq!("new@mycorp.com", database.get_user_by_id(1)?.unwrap().email);
+            Err(DatabaseError::ConcurrencyConflict {
+                company_id: 1,
+                expected_version: 0,
+            })
+        });
+        let user = sut.get_user_by_id(1)?.unwrap();
+
+        // Assert
+        assert!(result.is_err());
+        assert_eq!("user@mycorp.com", user.email);
+        assert_eq!(CacheStats { hits: 1, misses: 1 }, sut.stats());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rows_cached_during_a_transaction_are_dropped_when_it_commits(
+    ) -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let path = env::temp_dir().join(format!("cache-{}.db", Uuid::new_v4()));
+        let database = PooledSQLiteDatabase::open(&path, PoolOptions::default())?;
+        database.insert_user("user@mycorp.com", UserType::Employee)?;
+        let sut = Arc::new(CachingDatabase::new(database, CacheOptions::default()));
+
+        // Act
+        sut.in_transaction(|database| -> Result<(), DatabaseError> {
+            let mut user = database.get_user_by_id(1)?.unwrap();
+            user.email = "new@mycorp.com".to_owned();
+            database.save_user(&user)?;
+
+            // Another thread still sees, and caches, the committed row.
+            let other_thread = Arc::clone(&sut);
+            let seen = thread::spawn(move || other_thread.get_user_by_id(1))
+                .join()
+                .unwrap()?;
+            assert_eq!("user@mycorp.com", seen.unwrap().email);
+            Ok(())
+        })?;
+        let user = sut.get_user_by_id(1)?.unwrap();
+
+        // Assert
+        assert_eq!("new@mycorp.com", user.email);
+
+        drop(sut);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn a_read_racing_a_commit_does_not_cache_the_replaced_row() -> Result<(), Box<dyn error::Error>>
+    {
+        // Arrange
+        let sut = CachingDatabase::new(get_db()?, CacheOptions::default());
+
+        // Act
+        // The steps of a cache miss, with another writer committing between the
+        // read and the caching of its result.
+        let user_generation = sut.user_generation(1);
+        let company_generation = sut.company_generation.load(Ordering::SeqCst);
+        let stale_user = sut.inner().get_user_by_id(1)?.unwrap();
+        let stale_company = sut.inner().get_company()?.unwrap();
+
+        let mut user = stale_user.clone();
+        user.email = "new@mycorp.com".to_owned();
+        sut.save_user(&user)?;
+        let mut company = stale_company.clone();
+        company.number_of_employees = 3;
+        sut.save_company(&company)?;
+
+        sut.cache_user(&stale_user, user_generation);
+        sut.cache_company(&stale_company, company_generation);
+
+        // Assert
+        assert_eq!("new@mycorp.com", sut.get_user_by_id(1)?.unwrap().email);
+        assert_eq!(3, sut.get_company()?.unwrap().number_of_employees);
+        assert_eq!(CacheStats { hits: 0, misses: 2 }, sut.stats());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,198 @@
+use chrono::{DateTime, Utc};
+
+use super::bus_message::MessageEnvelope;
+
+/// A message the bus still rejected after every retry, kept for inspection and
+/// replay instead of being dropped.
+#[derive(Debug, PartialEq)]
+pub struct DeadLetter {
+    pub id: i64,
+    pub envelope: MessageEnvelope,
+    pub error: String,
+    pub failed_at: DateTime<Utc>,
+    /// The subscribers that failed, and the only ones a replay goes to. Empty for
+    /// dead letters stored before these were recorded, which go to every subscriber.
+    pub subscribers: Vec<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::error;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use crate::ch_09::bus_message::MessageEnvelope;
+
+    use crate::ch_09::outbox::OutboxRelay;
+    use crate::ch_09::retry::RetryPolicy;
+    use crate::ch_09::sqlite_database::SQLiteDatabase;
+    use crate::ch_09::subscribers::{EventHandler, HandlerError, SubscriberRegistry};
+    use crate::ch_09::test_helper::test_helper::create_db;
+    use crate::ch_09::types::*;
+
+    fn email_changed(user_id: i64) -> DomainEvent {
+        DomainEvent::EmailChangeEvent {
+            user_id,
+            new_email: format!("user{}@example.com", user_id),
+        }
+    }
+
+    fn dispatcher(bus: MockBus) -> EventDispatcher {
+        let retry_policy = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::ZERO,
+            ..RetryPolicy::default()
+        };
+        EventDispatcher::new(
+            MessageBus::new(bus).with_retry_policy(retry_policy),
+            MockDomainLogger::new(),
+        )
+    }
+
+    #[test]
+    fn transient_failures_are_retried() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let db = SQLiteDatabase::new(create_db()?);
+        db.add_to_outbox(&[email_changed(1)])?;
+        let failures = Cell::new(2);
+        let mut bus_mock = MockBus::new();
+        bus_mock.expect_send().times(3).returning(move |_| {
+            if failures.get() == 0 {
+                return Ok(());
+            }
+            failures.set(failures.get() - 1);
+            Err(BusError::from("broker unavailable"))
+        });
+        let dispatcher = dispatcher(bus_mock);
+
+        // Act
+        let relayed = OutboxRelay::new(&db, &dispatcher).relay_pending()?;
+
+        // Assert
+        assert_eq!(1, relayed);
+        assert!(db.get_dead_letters()?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn exhausted_messages_are_dead_lettered_and_can_be_replayed(
+    ) -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let db = SQLiteDatabase::new(create_db()?);
+        db.add_to_outbox(&[email_changed(1), email_changed(2)])?;
+        let stored = db.get_undelivered_messages(10)?;
+        let mut failing_bus = MockBus::new();
+        failing_bus
+            .expect_send()
+            .times(6)
+            .returning(|_| Err(BusError::from("broker unavailable")));
+        let failing = dispatcher(failing_bus);
+
+        // Act
+        let relayed = OutboxRelay::new(&db, &failing).relay_pending()?;
+
+        // Assert
+        assert_eq!(0, relayed);
+        assert!(db.get_undelivered_messages(10)?.is_empty());
+        let dead_letters = db.get_dead_letters()?;
+        assert_eq!(
+            stored.into_iter().map(|m| m.envelope).collect::<Vec<_>>(),
+            dead_letters
+                .iter()
+                .map(|d| d.envelope.clone())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!("message_bus: broker unavailable", dead_letters[0].error);
+
+        // Act
+        let mut working_bus = MockBus::new();
+        working_bus.expect_send().times(2).returning(|_| Ok(()));
+        let working = dispatcher(working_bus);
+        let replayed = OutboxRelay::new(&db, &working).replay_dead_letters()?;
+
+        // Assert
+        assert_eq!(2, replayed);
+        assert!(db.get_dead_letters()?.is_empty());
+
+        Ok(())
+    }
+
+    /// Records each call under its name, failing while `failing` lists it.
+    struct Subscriber {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<&'static str>>>,
+        failing: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl EventHandler for Subscriber {
+        fn handle(&self, _: &MessageEnvelope) -> Result<(), HandlerError> {
+            self.calls.lock().unwrap().push(self.name);
+            if self.failing.lock().unwrap().contains(&self.name) {
+                return Err("unavailable".into());
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn replay_only_reaches_the_subscribers_that_failed() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let db = SQLiteDatabase::new(create_db()?);
+        db.add_to_outbox(&[email_changed(1)])?;
+        let calls = Arc::new(Mutex::new(vec![]));
+        let failing = Arc::new(Mutex::new(vec!["crm", "hr"]));
+        let mut subscribers = SubscriberRegistry::new();
+        for name in ["audit", "crm", "hr"] {
+            subscribers.subscribe_all(
+                name,
+                Subscriber {
+                    name,
+                    calls: calls.clone(),
+                    failing: failing.clone(),
+                },
+            );
+        }
+        let dispatcher = EventDispatcher::with_subscribers(subscribers);
+        let sut = OutboxRelay::new(&db, &dispatcher);
+        sut.relay_pending()?;
+
+        // Act
+        failing.lock().unwrap().retain(|&name| name != "crm");
+        let first_replay = sut.replay_dead_letters()?;
+        failing.lock().unwrap().clear();
+        let second_replay = sut.replay_dead_letters()?;
+
+        // Assert
+        assert_eq!((0, 1), (first_replay, second_replay));
+        assert_eq!(
+            vec!["audit", "crm", "hr", "crm", "hr", "hr"],
+            *calls.lock().unwrap()
+        );
+        assert!(db.get_dead_letters()?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn dead_letters_record_the_subscribers_that_failed() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let db = SQLiteDatabase::new(create_db()?);
+        db.add_to_outbox(&[email_changed(1)])?;
+        let mut failing_bus = MockBus::new();
+        failing_bus
+            .expect_send()
+            .returning(|_| Err(BusError::from("broker unavailable")));
+        let dispatcher = dispatcher(failing_bus);
+
+        // Act
+        OutboxRelay::new(&db, &dispatcher).relay_pending()?;
+
+        // Assert
+        let dead_letters = db.get_dead_letters()?;
+        assert_eq!(vec!["message_bus".to_owned()], dead_letters[0].subscribers);
+
+        Ok(())
+    }
+}
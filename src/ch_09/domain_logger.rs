@@ -0,0 +1,123 @@
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+
+use super::types::{DomainLogger, UserType};
+use crate::ch_06_audit_log::sample_03::{ApplicationService, AuditManager};
+
+const USER_TYPE_CHANGED: &str = "USER_TYPE_CHANGED";
+
+/// Writes domain events to the audit log as hash-chained `key=value` records.
+/// Give it a directory of its own: unsealed records, like visits, fail
+/// verification.
+pub struct AuditDomainLogger {
+    // Appending reads the current file and rewrites it, so writes must not overlap.
+    audit_log: Mutex<ApplicationService>,
+    clock: fn() -> DateTime<Utc>,
+}
+
+impl AuditDomainLogger {
+    pub fn new(directory_name: impl Into<String>, max_entries_per_file: usize) -> Self {
+        Self {
+            audit_log: Mutex::new(ApplicationService::new(
+                directory_name,
+                AuditManager::new(max_entries_per_file),
+            )),
+            clock: Utc::now,
+        }
+    }
+}
+
+impl DomainLogger for AuditDomainLogger {
+    fn user_type_has_changed(&self, user_id: i64, old_type: UserType, new_type: UserType) {
+        let record = format!(
+            "event={}; user_id={}; old_type={}; new_type={}; timestamp={}",
+            USER_TYPE_CHANGED,
+            user_id,
+            old_type,
+            new_type,
+            (self.clock)().to_rfc3339()
+        );
+
+        // Logging must not fail the use case that raised the event.
+        if let Err(e) = self.audit_log.lock().unwrap().add_sealed_entry(&record) {
+            tracing::warn!(error = %e, record, "failed to write audit record");
+        }
+    }
+}
+
+/// Emits domain events as `tracing` events; the subscriber adds the timestamp.
+#[derive(Default)]
+pub struct TracingDomainLogger;
+
+impl DomainLogger for TracingDomainLogger {
+    fn user_type_has_changed(&self, user_id: i64, old_type: UserType, new_type: UserType) {
+        tracing::info!(
+            event = USER_TYPE_CHANGED,
+            user_id,
+            old_type = %old_type,
+            new_type = %new_type,
+            "user type changed"
+        );
+    }
+}
+
+/// A logger picked at runtime, e.g. from configuration, when building an
+/// `EventDispatcher`.
+pub enum DomainLoggerBackend {
+    Audit(AuditDomainLogger),
+    Tracing(TracingDomainLogger),
+}
+
+impl DomainLogger for DomainLoggerBackend {
+    fn user_type_has_changed(&self, user_id: i64, old_type: UserType, new_type: UserType) {
+        match self {
+            Self::Audit(logger) => logger.user_type_has_changed(user_id, old_type, new_type),
+            Self::Tracing(logger) => logger.user_type_has_changed(user_id, old_type, new_type),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, error, fs};
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn type_changes_are_written_to_the_audit_log() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let directory = env::temp_dir().join(format!("audit-{}", Uuid::new_v4()));
+        fs::create_dir(&directory)?;
+        let mut sut = AuditDomainLogger::new(directory.to_str().unwrap(), 2);
+        sut.clock = || "2023-03-01T12:00:00Z".parse().unwrap();
+
+        // Act
+        sut.user_type_has_changed(1, UserType::Employee, UserType::Cusotmer);
+        sut.user_type_has_changed(2, UserType::Cusotmer, UserType::Employee);
+        sut.user_type_has_changed(3, UserType::Cusotmer, UserType::Admin);
+
+        // Assert
+        let first_file = fs::read_to_string(directory.join("audit_1.txt"))?;
+        let entries: Vec<_> = first_file
+            .lines()
+            .map(|line| line.split("; hash=").next().unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                "event=USER_TYPE_CHANGED; user_id=1; old_type=EMPLOYEE; new_type=CUSTOMER; \
+                 timestamp=2023-03-01T12:00:00+00:00",
+                "event=USER_TYPE_CHANGED; user_id=2; old_type=CUSTOMER; new_type=EMPLOYEE; \
+                 timestamp=2023-03-01T12:00:00+00:00",
+            ],
+            entries
+        );
+        assert!(fs::read_to_string(directory.join("audit_2.txt"))?.contains("user_id=3"));
+        sut.audit_log.lock().unwrap().verify()?;
+
+        fs::remove_dir_all(directory)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+use chrono::{DateTime, Duration, Utc};
+use uuid::Uuid;
+
+/// How long a confirmation link stays valid after it has been sent.
+pub const TOKEN_LIFETIME_HOURS: i64 = 24;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ConfirmationToken {
+    pub token: String,
+    pub user_id: i64,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum TokenError {
+    #[error("Confirmation token has expired")]
+    Expired,
+    #[error("Confirmation token has already been used")]
+    AlreadyUsed,
+}
+
+impl ConfirmationToken {
+    pub fn issue(user_id: i64, now: DateTime<Utc>) -> Self {
+        Self {
+            token: Uuid::new_v4().simple().to_string(),
+            user_id,
+            expires_at: now + Duration::hours(TOKEN_LIFETIME_HOURS),
+            used_at: None,
+        }
+    }
+
+    /// Marks the token as used, failing if it has been used before or has expired.
+    pub fn redeem(&mut self, now: DateTime<Utc>) -> Result<(), TokenError> {
+        if self.used_at.is_some() {
+            return Err(TokenError::AlreadyUsed);
+        }
+        if now >= self.expires_at {
+            return Err(TokenError::Expired);
+        }
+
+        self.used_at = Some(now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error;
+
+    use crate::ch_09::bus_message::MessageFormat;
+    use crate::ch_09::sqlite_database::SQLiteDatabase;
+    use crate::ch_09::test_helper::test_helper::{create_db, create_user};
+    use crate::ch_09::types::*;
+
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        "2023-02-18T12:00:00Z".parse().unwrap()
+    }
+
+    fn get_db() -> SQLiteDatabase {
+        SQLiteDatabase::new(create_db().unwrap())
+    }
+
+    fn create_unconfirmed_user(db: &mut SQLiteDatabase) -> Result<User, Box<dyn error::Error>> {
+        let mut user = create_user(&mut db.conn, "user@example.com", UserType::Cusotmer)?;
+        user.email_confirmed = false;
+        db.save_user(&user)?;
+
+        Ok(user)
+    }
+
+    fn logger() -> MockDomainLogger {
+        MockDomainLogger::new()
+    }
+
+    #[test]
+    fn a_token_cannot_be_redeemed_twice() {
+        let mut sut = ConfirmationToken::issue(1, now());
+
+        assert_eq!(Ok(()), sut.redeem(now()));
+        assert_eq!(Err(TokenError::AlreadyUsed), sut.redeem(now()));
+    }
+
+    #[test]
+    fn a_token_cannot_be_redeemed_after_it_expires() {
+        let mut sut = ConfirmationToken::issue(1, now());
+
+        let result = sut.redeem(now() + Duration::hours(TOKEN_LIFETIME_HOURS));
+
+        assert_eq!(Err(TokenError::Expired), result);
+        assert_eq!(None, sut.used_at);
+    }
+
+    #[test]
+    fn requesting_confirmation_sends_a_token() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let mut db = get_db();
+        let user = create_unconfirmed_user(&mut db)?;
+
+        let mut bus_mock = MockBus::new();
+        bus_mock
+            .expect_send()
+            .withf(|message| {
+                message.contains("\"event_type\":\"EMAIL_CONFIRMATION_REQUESTED\"")
+                    && message.contains("\"email\":\"user@example.com\"")
+            })
+            .times(1)
+            .return_once(|_| Ok(()));
+        let sut = UserController::new(
+            db,
+            EventDispatcher::new(
+                MessageBus::with_format(bus_mock, MessageFormat::Json),
+                logger(),
+            ),
+        );
+
+        // Act
+        let result = sut.request_email_confirmation(user.user_id, now());
+
+        // Assert
+        assert!(result.is_ok());
+        let token: String = sut.database.conn.query_row(
+            "SELECT token FROM email_confirmation_token",
+            [],
+            |row| row.get(0),
+        )?;
+        let stored = sut.database.get_confirmation_token(&token)?.unwrap();
+        assert_eq!(user.user_id, stored.user_id);
+        assert_eq!(
+            now() + Duration::hours(TOKEN_LIFETIME_HOURS),
+            stored.expires_at
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn confirming_email_flips_the_flag_and_raises_an_event() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let mut db = get_db();
+        let user = create_unconfirmed_user(&mut db)?;
+        let token = ConfirmationToken::issue(user.user_id, now());
+        db.save_confirmation_token(&token)?;
+
+        let mut bus_mock = MockBus::new();
+        bus_mock
+            .expect_send()
+            .withf(|message| message.contains("\"event_type\":\"USER_EMAIL_CONFIRMED\""))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let sut = UserController::new(
+            db,
+            EventDispatcher::new(
+                MessageBus::with_format(bus_mock, MessageFormat::Json),
+                logger(),
+            ),
+        );
+
+        // Act
+        let first = sut.confirm_email(&token.token, now());
+        let second = sut.confirm_email(&token.token, now());
+
+        // Assert
+        assert!(first.is_ok());
+        assert!(second.is_err());
+        let user_from_db = sut.database.get_user_by_id(user.user_id)?.unwrap();
+        assert!(user_from_db.email_confirmed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn expired_token_leaves_email_unconfirmed() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let mut db = get_db();
+        let user = create_unconfirmed_user(&mut db)?;
+        let token = ConfirmationToken::issue(user.user_id, now());
+        db.save_confirmation_token(&token)?;
+
+        let mut bus_mock = MockBus::new();
+        bus_mock.expect_send().times(0);
+        let sut = UserController::new(
+            db,
+            EventDispatcher::new(MessageBus::new(bus_mock), logger()),
+        );
+
+        // Act
+        let result = sut.confirm_email(&token.token, now() + Duration::days(2));
+
+        // Assert
+        assert!(result.is_err());
+        let user_from_db = sut.database.get_user_by_id(user.user_id)?.unwrap();
+        assert!(!user_from_db.email_confirmed);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use super::types::UserType;
+
+/// Recorded as the actor of changes made without an identified caller.
+pub const SYSTEM_ACTOR: &str = "system";
+
+/// One change of a user's email address, and of the type it implied.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct EmailChange {
+    pub user_id: i64,
+    pub old_email: String,
+    pub new_email: String,
+    pub old_type: UserType,
+    pub new_type: UserType,
+    pub changed_at: DateTime<Utc>,
+    pub actor: String,
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Duration, Utc};
+    use std::{error, thread};
+
+    use crate::ch_09::sqlite_database::SQLiteDatabase;
+    use crate::ch_09::test_helper::test_helper::{create_company, create_db, create_user};
+    use crate::ch_09::types::*;
+
+    use super::*;
+
+    fn sut() -> Result<UserController<SQLiteDatabase>, Box<dyn error::Error>> {
+        let mut db = SQLiteDatabase::new(create_db()?);
+        create_user(&mut db.conn, "user@mycorp.com", UserType::Employee)?;
+        create_user(&mut db.conn, "other@example.com", UserType::Cusotmer)?;
+        create_company(&mut db.conn, "mycorp.com", 1)?;
+
+        let mut bus_mock = MockBus::new();
+        bus_mock.expect_send().returning(|_| Ok(()));
+        let mut domain_logger_mock = MockDomainLogger::new();
+        domain_logger_mock
+            .expect_user_type_has_changed()
+            .returning(|_, _, _| {});
+
+        Ok(UserController::new(
+            db,
+            EventDispatcher::new(MessageBus::new(bus_mock), domain_logger_mock),
+        ))
+    }
+
+    #[test]
+    fn email_changes_are_recorded_with_their_actor() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let sut = sut()?;
+
+        // Act
+        sut.change_email_as(1, "new@example.com", "support@mycorp.com")?;
+        sut.change_email(1, "newer@example.com")?;
+
+        // Assert
+        let history = sut.database.get_email_history(1)?;
+        assert_eq!(2, history.len());
+        assert_eq!("user@mycorp.com", history[0].old_email);
+        assert_eq!("new@example.com", history[0].new_email);
+        assert_eq!(UserType::Employee, history[0].old_type);
+        assert_eq!(UserType::Cusotmer, history[0].new_type);
+        assert_eq!("support@mycorp.com", history[0].actor);
+        assert_eq!("newer@example.com", history[1].new_email);
+        assert_eq!(SYSTEM_ACTOR, history[1].actor);
+        assert!(sut.database.get_email_history(2)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn finds_who_owned_an_address_at_a_point_in_time() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let sut = sut()?;
+        let before = Utc::now() - Duration::seconds(1);
+        sut.change_email(1, "shared@example.com")?;
+        thread::sleep(std::time::Duration::from_millis(5));
+        let while_first_owned = Utc::now();
+        thread::sleep(std::time::Duration::from_millis(5));
+        sut.change_email(1, "moved@example.com")?;
+        sut.change_email(2, "shared@example.com")?;
+
+        // Act & Assert
+        let owner = |email, at| sut.database.find_email_owner(email, at);
+        assert_eq!(None, owner("shared@example.com", before)?);
+        assert_eq!(Some(1), owner("user@mycorp.com", before)?);
+        assert_eq!(Some(2), owner("other@example.com", before)?);
+        assert_eq!(Some(1), owner("shared@example.com", while_first_owned)?);
+        assert_eq!(Some(2), owner("shared@example.com", Utc::now())?);
+        assert_eq!(None, owner("user@mycorp.com", Utc::now())?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn users_registered_later_owned_nothing_before() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let sut = sut()?;
+        let before = Utc::now() - Duration::seconds(1);
+
+        // Act
+        let user_id = sut.register_user("late@example.com")?;
+
+        // Assert
+        let owner = |email, at| sut.database.find_email_owner(email, at);
+        assert_eq!(None, owner("late@example.com", before)?);
+        assert_eq!(Some(user_id), owner("late@example.com", Utc::now())?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn deleted_users_own_their_address_only_until_deleted() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let sut = sut()?;
+        let before = Utc::now() - Duration::seconds(1);
+
+        // Act
+        sut.delete_user(2)?;
+
+        // Assert
+        let owner = |email, at| sut.database.find_email_owner(email, at);
+        assert_eq!(Some(2), owner("other@example.com", before)?);
+        assert_eq!(None, owner("other@example.com", Utc::now())?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn the_earliest_user_owns_a_shared_address() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let sut = sut()?;
+
+        // Act
+        sut.change_email(2, "user@mycorp.com")?;
+
+        // Assert
+        assert_eq!(
+            Some(1),
+            sut.database
+                .find_email_owner("user@mycorp.com", Utc::now())?
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+use chrono::Utc;
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::types::{DatabaseError, DomainEvent, User, UserStatus, UserType};
+
+/// Appends user domain events to a per-user stream and rebuilds users by replaying
+/// them. A snapshot of the replayed state is stored every `snapshot_interval`
+/// events so loading never replays more than that many.
+///
+/// A stream starts with a snapshot at sequence 0 holding the user's state before
+/// their first recorded event, so the stream alone describes their full history.
+pub struct EventStore {
+    snapshot_interval: i64,
+}
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+#[error("snapshot interval must be positive, got {0}")]
+pub struct InvalidSnapshotInterval(pub i64);
+
+/// The persisted state of a `User`, as stored in snapshots.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct UserSnapshot {
+    pub user_id: i64,
+    pub email: String,
+    pub email_confirmed: bool,
+    pub user_type: UserType,
+    /// Absent from snapshots taken before users could be deactivated.
+    #[serde(default)]
+    pub status: UserStatus,
+}
+
+impl EventStore {
+    pub fn new(snapshot_interval: i64) -> Result<Self, InvalidSnapshotInterval> {
+        if snapshot_interval <= 0 {
+            return Err(InvalidSnapshotInterval(snapshot_interval));
+        }
+
+        Ok(Self { snapshot_interval })
+    }
+
+    /// Appends `events` to the user's stream, starting it from `current` if the user
+    /// has no stream yet. Two writers appending at the same sequence conflict on the
+    /// stream's primary key, so run this in the same transaction as the state change.
+    pub fn append(
+        &self,
+        conn: &Connection,
+        current: &User,
+        events: &[DomainEvent],
+    ) -> Result<i64, DatabaseError> {
+        let mut sequence = match self.last_sequence(conn, current.user_id)? {
+            Some(sequence) => sequence,
+            None => {
+                self.save_snapshot(conn, &UserSnapshot::from(current), 0)?;
+                0
+            }
+        };
+        let first = sequence;
+
+        let mut stmt = conn.prepare(
+            "INSERT INTO user_event (user_id, sequence, event_id, event, occurred_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        for event in events {
+            sequence += 1;
+            stmt.execute((
+                current.user_id,
+                sequence,
+                Uuid::new_v4().to_string(),
+                serde_json::to_string(event)?,
+                Utc::now(),
+            ))?;
+        }
+
+        if first / self.snapshot_interval != sequence / self.snapshot_interval {
+            if let Some(user) = self.load(conn, current.user_id)? {
+                self.save_snapshot(conn, &UserSnapshot::from(&user), sequence)?;
+            }
+        }
+
+        Ok(sequence)
+    }
+
+    /// Rebuilds the user from their latest snapshot and the events after it.
+    pub fn load(&self, conn: &Connection, user_id: i64) -> Result<Option<User>, DatabaseError> {
+        let snapshot = conn
+            .query_row(
+                "SELECT sequence, state FROM user_snapshot
+                 WHERE user_id = ?1 ORDER BY sequence DESC LIMIT 1",
+                [user_id],
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()?;
+        let Some((sequence, state)) = snapshot else {
+            return Ok(None);
+        };
+
+        let snapshot: UserSnapshot = serde_json::from_str(&state)?;
+        let events = self.events_after(conn, user_id, sequence)?;
+
+        Ok(Some(User::rehydrate(snapshot, &events)))
+    }
+
+    /// Every event in the user's stream, oldest first.
+    pub fn history(
+        &self,
+        conn: &Connection,
+        user_id: i64,
+    ) -> Result<Vec<DomainEvent>, DatabaseError> {
+        self.events_after(conn, user_id, 0)
+    }
+
+    fn events_after(
+        &self,
+        conn: &Connection,
+        user_id: i64,
+        sequence: i64,
+    ) -> Result<Vec<DomainEvent>, DatabaseError> {
+        let mut stmt = conn.prepare(
+            "SELECT event FROM user_event WHERE user_id = ?1 AND sequence > ?2 ORDER BY sequence",
+        )?;
+        let events = stmt
+            .query_map((user_id, sequence), |row| row.get::<_, String>(0))?
+            .map(|event| Ok(serde_json::from_str(&event?)?))
+            .collect::<Result<Vec<_>, DatabaseError>>()?;
+
+        Ok(events)
+    }
+
+    fn last_sequence(&self, conn: &Connection, user_id: i64) -> Result<Option<i64>, DatabaseError> {
+        let sequence = conn.query_row(
+            "SELECT MAX(sequence) FROM (
+                SELECT sequence FROM user_event WHERE user_id = ?1
+                UNION ALL
+                SELECT sequence FROM user_snapshot WHERE user_id = ?1
+            )",
+            [user_id],
+            |row| row.get(0),
+        )?;
+
+        Ok(sequence)
+    }
+
+    fn save_snapshot(
+        &self,
+        conn: &Connection,
+        snapshot: &UserSnapshot,
+        sequence: i64,
+    ) -> Result<(), DatabaseError> {
+        conn.execute(
+            "INSERT INTO user_snapshot (user_id, sequence, state) VALUES (?1, ?2, ?3)",
+            (snapshot.user_id, sequence, serde_json::to_string(snapshot)?),
+        )?;
+
+        Ok(())
+    }
+}
+
+impl From<&User> for UserSnapshot {
+    fn from(user: &User) -> Self {
+        Self {
+            user_id: user.user_id,
+            email: user.email.clone(),
+            email_confirmed: user.email_confirmed,
+            user_type: user.user_type,
+            status: user.status,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error;
+
+    use crate::ch_09::sqlite_database::SQLiteDatabase;
+    use crate::ch_09::test_helper::test_helper::{create_company, create_db, create_user};
+    use crate::ch_09::types::*;
+
+    use super::*;
+
+    fn count(db: &SQLiteDatabase, table: &str) -> i64 {
+        db.conn
+            .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn user_is_rehydrated_from_its_events() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let mut db = SQLiteDatabase::with_event_sourcing(create_db()?, EventStore::new(10)?);
+        create_user(&mut db.conn, "user@mycorp.com", UserType::Employee)?;
+        create_company(&mut db.conn, "mycorp.com", 1)?;
+
+        // Act
+        for new_email in ["a@example.com", "b@mycorp.com", "c@example.com"] {
+            let mut user = db.get_user_by_id(1)?.unwrap();
+            let mut company = db.get_company()?.unwrap();
+            user.change_email(new_email, &mut company)?;
+            db.save_company(&company)?;
+            db.save_user(&user)?;
+        }
+
+        // Assert
+        db.conn
+            .execute("UPDATE user SET email = 'stale@example.com'", ())?;
+        let user = db.get_user_by_id(1)?.unwrap();
+        assert_eq!("c@example.com", user.email);
+        assert_eq!(UserType::Cusotmer, user.user_type);
+        assert_eq!(6, EventStore::new(10)?.history(&db.conn, 1)?.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn snapshots_bound_the_events_replayed() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let mut db = SQLiteDatabase::with_event_sourcing(create_db()?, EventStore::new(2)?);
+        create_user(&mut db.conn, "user@example.com", UserType::Cusotmer)?;
+        create_company(&mut db.conn, "mycorp.com", 0)?;
+
+        // Act
+        for i in 0..5 {
+            let mut user = db.get_user_by_id(1)?.unwrap();
+            let mut company = db.get_company()?.unwrap();
+            user.change_email(&format!("user{}@example.com", i), &mut company)?;
+            db.save_user(&user)?;
+        }
+
+        // Assert
+        assert_eq!(5, count(&db, "user_event"));
+        // The initial snapshot plus one after events 2 and 4.
+        assert_eq!(3, count(&db, "user_snapshot"));
+        let user = db.get_user_by_id(1)?.unwrap();
+        assert_eq!("user4@example.com", user.email);
+
+        Ok(())
+    }
+
+    #[test]
+    fn without_event_sourcing_no_events_are_stored() -> Result<(), Box<dyn error::Error>> {
+        let mut db = SQLiteDatabase::new(create_db()?);
+        create_user(&mut db.conn, "user@example.com", UserType::Cusotmer)?;
+        create_company(&mut db.conn, "mycorp.com", 0)?;
+
+        let mut user = db.get_user_by_id(1)?.unwrap();
+        let mut company = db.get_company()?.unwrap();
+        user.change_email("new@example.com", &mut company)?;
+        db.save_user(&user)?;
+
+        assert_eq!(0, count(&db, "user_event"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn registration_starts_the_stream() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let mut db = SQLiteDatabase::with_event_sourcing(create_db()?, EventStore::new(10)?);
+        create_company(&mut db.conn, "mycorp.com", 0)?;
+
+        // Act
+        let user = register_user_in(&db, "new@mycorp.com", Utc::now())?;
+
+        // Assert
+        let history = EventStore::new(10)?.history(&db.conn, user.user_id)?;
+        assert!(matches!(
+            history.as_slice(),
+            [
+                DomainEvent::UserRegisteredEvent { .. },
+                DomainEvent::EmailConfirmationRequestedEvent { .. }
+            ]
+        ));
+        db.conn
+            .execute("UPDATE user SET email_confirmed = TRUE", ())?;
+        assert!(!db.get_user_by_id(user.user_id)?.unwrap().email_confirmed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_interval_must_be_positive() {
+        assert!(matches!(
+            EventStore::new(0),
+            Err(InvalidSnapshotInterval(0))
+        ));
+    }
+}
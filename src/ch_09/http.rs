@@ -0,0 +1,399 @@
+//! JSON over HTTP for the user management use cases.
+//!
+//! | Method | Path                              | Body                  |
+//! |--------|-----------------------------------|-----------------------|
+//! | GET    | `/users/{id}`                     |                       |
+//! | POST   | `/users/{id}/email`               | `{"email": "..."}`    |
+//! | POST   | `/email-confirmations/{token}`    |                       |
+//! | GET    | `/company`                        |                       |
+
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use super::asynchronous::{AsyncDatabase, AsyncUserController};
+use super::email_confirmation::TokenError;
+use super::types::{Company, Database, DatabaseError, User, UserManagementError, UserType};
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct UserResponse {
+    pub user_id: i64,
+    pub email: String,
+    pub email_confirmed: bool,
+    pub user_type: UserType,
+}
+
+impl From<User> for UserResponse {
+    fn from(user: User) -> Self {
+        Self {
+            user_id: user.user_id,
+            email: user.email,
+            email_confirmed: user.email_confirmed,
+            user_type: user.user_type,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct CompanyResponse {
+    pub company_id: i64,
+    pub domain_name: String,
+    pub number_of_employees: i64,
+}
+
+impl From<Company> for CompanyResponse {
+    fn from(company: Company) -> Self {
+        Self {
+            company_id: company.id,
+            domain_name: company.domain_name,
+            number_of_employees: company.number_of_employees,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangeEmailRequest {
+    pub email: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: String,
+    /// Every violated rule, when a request broke several at once.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<String>,
+}
+
+/// A `UserManagementError` as an HTTP response. Storage and messaging failures
+/// are logged and hidden behind a generic 500.
+pub struct ApiError(UserManagementError);
+
+impl From<UserManagementError> for ApiError {
+    fn from(e: UserManagementError) -> Self {
+        Self(e)
+    }
+}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match &self.0 {
+            UserManagementError::UserNotFound(_)
+            | UserManagementError::CompanyNotFound
+            | UserManagementError::ConfirmationTokenNotFound => StatusCode::NOT_FOUND,
+            UserManagementError::InvalidEmail(_) | UserManagementError::PreconditionsFailed(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            UserManagementError::EmailNotConfirmed(_)
+            | UserManagementError::EmailAlreadyConfirmed(_)
+            | UserManagementError::EmailAlreadyInUse(_)
+            | UserManagementError::EmployeeCountUnderflow { .. } => StatusCode::CONFLICT,
+            UserManagementError::InvalidConfirmationToken(
+                TokenError::Expired | TokenError::AlreadyUsed,
+            )
+            | UserManagementError::UserDeleted(_) => StatusCode::GONE,
+            UserManagementError::Storage(e)
+                if matches!(
+                    e.downcast_ref::<DatabaseError>(),
+                    Some(DatabaseError::ConcurrencyConflict { .. })
+                ) =>
+            {
+                StatusCode::CONFLICT
+            }
+            UserManagementError::Storage(_) | UserManagementError::Messaging(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let body = if status == StatusCode::INTERNAL_SERVER_ERROR {
+            tracing::error!(error = ?self.0, "request failed");
+            ErrorResponse {
+                error: "internal error".to_owned(),
+                violations: vec![],
+            }
+        } else {
+            let violations = match &self.0 {
+                UserManagementError::PreconditionsFailed(violations) => {
+                    violations.iter().map(|v| v.to_string()).collect()
+                }
+                _ => vec![],
+            };
+            ErrorResponse {
+                error: self.0.to_string(),
+                violations,
+            }
+        };
+
+        (status, Json(body)).into_response()
+    }
+}
+
+/// Routes for the endpoints above, serving requests through `controller`.
+pub fn router<D>(controller: AsyncUserController<D>) -> Router
+where
+    D: AsyncDatabase + 'static,
+    UserManagementError: From<<D::Sync as Database>::Error>,
+{
+    Router::new()
+        .route("/users/{id}", get(get_user::<D>))
+        .route("/users/{id}/email", post(change_email::<D>))
+        .route("/email-confirmations/{token}", post(confirm_email::<D>))
+        .route("/company", get(get_company::<D>))
+        .with_state(Arc::new(controller))
+}
+
+type Controller<D> = State<Arc<AsyncUserController<D>>>;
+
+async fn get_user<D>(
+    State(controller): Controller<D>,
+    Path(user_id): Path<i64>,
+) -> Result<Json<UserResponse>, ApiError>
+where
+    D: AsyncDatabase,
+{
+    let user = controller
+        .database
+        .get_user_by_id(user_id)
+        .await?
+        .ok_or(UserManagementError::UserNotFound(user_id))?;
+
+    Ok(Json(user.into()))
+}
+
+async fn change_email<D>(
+    State(controller): Controller<D>,
+    Path(user_id): Path<i64>,
+    Json(request): Json<ChangeEmailRequest>,
+) -> Result<StatusCode, ApiError>
+where
+    D: AsyncDatabase,
+    UserManagementError: From<<D::Sync as Database>::Error>,
+{
+    controller.change_email(user_id, &request.email).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn confirm_email<D>(
+    State(controller): Controller<D>,
+    Path(token): Path<String>,
+) -> Result<StatusCode, ApiError>
+where
+    D: AsyncDatabase,
+    UserManagementError: From<<D::Sync as Database>::Error>,
+{
+    controller.confirm_email(&token, Utc::now()).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_company<D>(State(controller): Controller<D>) -> Result<Json<CompanyResponse>, ApiError>
+where
+    D: AsyncDatabase,
+{
+    let company = controller
+        .database
+        .get_company()
+        .await?
+        .ok_or(UserManagementError::CompanyNotFound)?;
+
+    Ok(Json(company.into()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::error;
+    use std::sync::Mutex;
+
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request;
+    use serde::de::DeserializeOwned;
+    use tower::ServiceExt;
+
+    use crate::ch_09::asynchronous::BlockingDatabase;
+    use crate::ch_09::email_confirmation::ConfirmationToken;
+    use crate::ch_09::pooled_database::PooledSQLiteDatabase;
+    use crate::ch_09::test_helper::test_helper::{create_pooled_db, insert_confirmed_user};
+    use crate::ch_09::types::{
+        Bus, BusError, Database as _, DomainLogger, EventDispatcher, MessageBus,
+    };
+
+    use super::*;
+
+    /// Records every message sent instead of delivering it.
+    #[derive(Clone, Default)]
+    struct BusSpy {
+        sent: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Bus for BusSpy {
+        fn send(&self, message: &str) -> Result<(), BusError> {
+            self.sent.lock().unwrap().push(message.to_owned());
+            Ok(())
+        }
+    }
+
+    impl BusSpy {
+        fn sent(&self) -> Vec<String> {
+            self.sent.lock().unwrap().clone()
+        }
+    }
+
+    struct NoopLogger;
+
+    impl DomainLogger for NoopLogger {
+        fn user_type_has_changed(&self, _: i64, _: UserType, _: UserType) {}
+    }
+
+    fn app(db: PooledSQLiteDatabase) -> (Router, BusSpy) {
+        let bus_spy = BusSpy::default();
+        let controller = AsyncUserController::new(
+            BlockingDatabase::new(db),
+            EventDispatcher::new(MessageBus::new(bus_spy.clone()), NoopLogger),
+        );
+
+        (router(controller), bus_spy)
+    }
+
+    fn get_db() -> Result<PooledSQLiteDatabase, Box<dyn error::Error>> {
+        let db = create_pooled_db()?;
+        insert_confirmed_user(&db, "user@mycorp.com", UserType::Employee)?;
+        let company_id = db.insert_company("mycorp.com")?;
+        db.adjust_employee_count(company_id, 1)?;
+
+        Ok(db)
+    }
+
+    fn unconfirm(db: &PooledSQLiteDatabase, user_id: i64) -> Result<(), Box<dyn error::Error>> {
+        let mut user = db.get_user_by_id(user_id)?.unwrap();
+        user.email_confirmed = false;
+        db.save_user(&user)?;
+
+        Ok(())
+    }
+
+    async fn call(app: &Router, method: &str, uri: &str, body: &str) -> (StatusCode, Vec<u8>) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_owned()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        (status, body.to_vec())
+    }
+
+    fn json<T: DeserializeOwned>(body: &[u8]) -> T {
+        serde_json::from_slice(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn changing_email_from_corporate_to_non_corporate() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let (app, bus_spy) = app(get_db()?);
+
+        // Act
+        let (status, _) = call(
+            &app,
+            "POST",
+            "/users/1/email",
+            r#"{"email": "new@example.com"}"#,
+        )
+        .await;
+
+        // Assert
+        assert_eq!(StatusCode::NO_CONTENT, status);
+        let (status, body) = call(&app, "GET", "/users/1", "").await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(
+            UserResponse {
+                user_id: 1,
+                email: "new@example.com".to_owned(),
+                email_confirmed: true,
+                user_type: UserType::Cusotmer,
+            },
+            json(&body)
+        );
+        let (_, body) = call(&app, "GET", "/company", "").await;
+        assert_eq!(0, json::<CompanyResponse>(&body).number_of_employees);
+        assert_eq!(
+            vec!["Type: USER EMAIL CHANGED; Id: 1; NewEmail: new@example.com"],
+            bus_spy.sent()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn domain_errors_map_to_status_codes() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let db = get_db()?;
+        unconfirm(&db, 1)?;
+        let (app, bus_spy) = app(db);
+
+        // Act & Assert
+        let (status, body) = call(&app, "GET", "/users/42", "").await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+        assert_eq!("user 42 not found", json::<ErrorResponse>(&body).error);
+
+        let (status, _) = call(
+            &app,
+            "POST",
+            "/users/1/email",
+            r#"{"email": "new@example.com"}"#,
+        )
+        .await;
+        assert_eq!(StatusCode::CONFLICT, status);
+
+        let (status, body) = call(&app, "POST", "/users/1/email", r#"{"email": "nope"}"#).await;
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, status);
+        assert_eq!(
+            vec!["email of user 1 is not confirmed", "invalid email: nope"],
+            json::<ErrorResponse>(&body).violations
+        );
+
+        let (status, _) = call(&app, "POST", "/email-confirmations/unknown", "").await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+        assert!(bus_spy.sent().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn confirmation_tokens_work_once() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let db = get_db()?;
+        unconfirm(&db, 1)?;
+        let token = ConfirmationToken::issue(1, Utc::now());
+        db.save_confirmation_token(&token)?;
+        let (app, bus_spy) = app(db);
+        let uri = format!("/email-confirmations/{}", token.token);
+
+        // Act
+        let (first, _) = call(&app, "POST", &uri, "").await;
+        let (second, _) = call(&app, "POST", &uri, "").await;
+
+        // Assert
+        assert_eq!(StatusCode::NO_CONTENT, first);
+        assert_eq!(StatusCode::GONE, second);
+        let (_, body) = call(&app, "GET", "/users/1", "").await;
+        assert!(json::<UserResponse>(&body).email_confirmed);
+        // Confirmations have no legacy message.
+        assert!(bus_spy.sent().is_empty());
+
+        Ok(())
+    }
+}
@@ -1,4 +1,22 @@
+pub mod asynchronous;
+pub mod bus;
+pub mod bus_message;
+pub mod caching_database;
+pub mod dead_letter;
+pub mod domain_logger;
+pub mod email_confirmation;
+pub mod email_history;
+pub mod event_store;
+pub mod http;
+pub mod outbox;
+pub mod pooled_database;
+pub mod preconditions;
+pub mod reconciliation;
+pub mod retry;
 mod sample_01;
 mod sample_02;
+pub mod sqlite_database;
+pub mod subscribers;
 mod test_helper;
-mod types;
+pub mod types;
+pub mod user_import;
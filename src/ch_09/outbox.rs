@@ -0,0 +1,223 @@
+use super::types::{Database, EventDispatcher};
+
+const BATCH_SIZE: usize = 100;
+
+/// Publishes domain events stored in the outbox and marks them as delivered.
+///
+/// A message is marked only after it has been dispatched, so a crash in between
+/// sends it again on the next run: delivery is at-least-once. A message the bus
+/// still rejects after its retries is moved to the dead-letter store instead, so
+/// it neither blocks the rest of the outbox nor gets lost.
+pub struct OutboxRelay<'a, D: Database> {
+    database: &'a D,
+    event_dispatcher: &'a EventDispatcher,
+}
+
+impl<'a, D: Database> OutboxRelay<'a, D> {
+    pub fn new(database: &'a D, event_dispatcher: &'a EventDispatcher) -> Self {
+        Self {
+            database,
+            event_dispatcher,
+        }
+    }
+
+    /// Relays every undelivered message and returns how many were sent.
+    pub fn relay_pending(&self) -> Result<usize, D::Error> {
+        let mut relayed = 0;
+
+        loop {
+            let messages = self.database.get_undelivered_messages(BATCH_SIZE)?;
+            if messages.is_empty() {
+                return Ok(relayed);
+            }
+
+            for message in messages {
+                match self.event_dispatcher.dispatch_envelope(&message.envelope) {
+                    Ok(()) => {
+                        self.database.mark_delivered(message.id)?;
+                        relayed += 1;
+                    }
+                    Err(e) => self.database.in_transaction(|database| {
+                        database.add_dead_letter(&message.envelope, &e)?;
+                        database.mark_delivered(message.id)
+                    })?,
+                }
+            }
+        }
+    }
+
+    /// Sends every dead letter again to the subscribers that failed it, and
+    /// returns how many went through. Those still rejected stay in the store,
+    /// narrowed down to the subscribers still failing.
+    pub fn replay_dead_letters(&self) -> Result<usize, D::Error> {
+        let mut replayed = 0;
+
+        for dead_letter in self.database.get_dead_letters()? {
+            let result = if dead_letter.subscribers.is_empty() {
+                self.event_dispatcher
+                    .dispatch_envelope(&dead_letter.envelope)
+            } else {
+                self.event_dispatcher
+                    .redispatch_envelope(&dead_letter.envelope, &dead_letter.subscribers)
+            };
+
+            match result {
+                Ok(()) => {
+                    self.database.mark_replayed(dead_letter.id)?;
+                    replayed += 1;
+                }
+                Err(e) if e.subscribers() != dead_letter.subscribers => {
+                    self.database.in_transaction(|database| {
+                        database.mark_replayed(dead_letter.id)?;
+                        database.add_dead_letter(&dead_letter.envelope, &e)
+                    })?
+                }
+                Err(_) => {}
+            }
+        }
+
+        Ok(replayed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mockall::predicate::eq;
+    use std::error;
+    use std::sync::{Arc, Mutex};
+
+    use crate::ch_09::bus_message::{parse_message, BusMessage, MessageFormat};
+    use crate::ch_09::sqlite_database::SQLiteDatabase;
+    use crate::ch_09::test_helper::test_helper::create_db;
+    use crate::ch_09::types::*;
+
+    use super::*;
+
+    #[test]
+    fn pending_messages_are_relayed_once() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let db = SQLiteDatabase::new(create_db()?);
+        db.add_to_outbox(&[
+            DomainEvent::UserTypeChangeEvent {
+                user_id: 1,
+                old_type: UserType::Employee,
+                new_type: UserType::Cusotmer,
+            },
+            DomainEvent::EmailChangeEvent {
+                user_id: 1,
+                new_email: "new@example.com".to_owned(),
+            },
+        ])?;
+
+        let mut bus_mock = MockBus::new();
+        bus_mock
+            .expect_send()
+            .with(eq(
+                "Type: USER EMAIL CHANGED; Id: 1; NewEmail: new@example.com",
+            ))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut domain_logger_mock = MockDomainLogger::new();
+        domain_logger_mock
+            .expect_user_type_has_changed()
+            .with(eq(1), eq(UserType::Employee), eq(UserType::Cusotmer))
+            .times(1)
+            .returning(|_, _, _| {});
+        let dispatcher = EventDispatcher::new(MessageBus::new(bus_mock), domain_logger_mock);
+        let sut = OutboxRelay::new(&db, &dispatcher);
+
+        // Act
+        let first_run = sut.relay_pending()?;
+        let second_run = sut.relay_pending()?;
+
+        // Assert
+        assert_eq!(2, first_run);
+        assert_eq!(0, second_run);
+        assert!(db.get_undelivered_messages(10)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn outbox_messages_are_read_back_in_order() -> Result<(), Box<dyn error::Error>> {
+        let db = SQLiteDatabase::new(create_db()?);
+        let events = vec![
+            DomainEvent::EmailChangeEvent {
+                user_id: 1,
+                new_email: "first@example.com".to_owned(),
+            },
+            DomainEvent::EmailChangeEvent {
+                user_id: 2,
+                new_email: "second@example.com".to_owned(),
+            },
+        ];
+        db.add_to_outbox(&events)?;
+
+        let messages = db.get_undelivered_messages(10)?;
+
+        assert_eq!(
+            events,
+            messages
+                .into_iter()
+                .map(|m| m.envelope.event)
+                .collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn json_bus_receives_every_event_with_its_stored_id() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let db = SQLiteDatabase::new(create_db()?);
+        db.add_to_outbox(&[
+            DomainEvent::UserTypeChangeEvent {
+                user_id: 1,
+                old_type: UserType::Employee,
+                new_type: UserType::Cusotmer,
+            },
+            DomainEvent::EmailChangeEvent {
+                user_id: 1,
+                new_email: "new@example.com".to_owned(),
+            },
+        ])?;
+        let stored = db.get_undelivered_messages(10)?;
+
+        let sent = Arc::new(Mutex::new(vec![]));
+        let sent_by_bus = sent.clone();
+        let mut bus_mock = MockBus::new();
+        bus_mock.expect_send().times(2).returning(move |message| {
+            sent_by_bus.lock().unwrap().push(message.to_owned());
+            Ok(())
+        });
+        let mut domain_logger_mock = MockDomainLogger::new();
+        domain_logger_mock
+            .expect_user_type_has_changed()
+            .times(1)
+            .returning(|_, _, _| {});
+        let dispatcher = EventDispatcher::new(
+            MessageBus::with_format(bus_mock, MessageFormat::Json),
+            domain_logger_mock,
+        );
+
+        // Act
+        OutboxRelay::new(&db, &dispatcher).relay_pending()?;
+
+        // Assert
+        let received = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|message| parse_message(message))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            stored
+                .into_iter()
+                .map(|m| BusMessage::Envelope(m.envelope))
+                .collect::<Vec<_>>(),
+            received
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,466 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Condvar, Mutex};
+use std::thread::{self, ThreadId};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rusqlite::Connection;
+
+use super::bus_message::MessageEnvelope;
+use super::dead_letter::DeadLetter;
+use super::email_confirmation::ConfirmationToken;
+use super::email_history::EmailChange;
+use super::sqlite_database::SQLiteDatabase;
+use super::subscribers::DispatchError;
+use super::types::*;
+use crate::migrations::migrate;
+
+/// How a `PooledSQLiteDatabase` opens and hands out connections.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct PoolOptions {
+    /// Connections opened up front. Always at least one.
+    pub size: usize,
+    /// How long a connection waits for another one's lock on the database, and
+    /// how long a caller waits for a free connection, before giving up.
+    pub busy_timeout: Duration,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            size: 4,
+            busy_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A `Database` that can be shared between threads, backed by a fixed pool of
+/// connections to one SQLite file in WAL mode.
+///
+/// Each call borrows a free connection for its duration. `begin` pins a connection
+/// to the calling thread, so everything that thread does until `commit` or
+/// `rollback` runs in the same transaction. `in_transaction` also rolls back if
+/// its unit of work panics.
+pub struct PooledSQLiteDatabase {
+    idle: Mutex<Vec<SQLiteDatabase>>,
+    released: Condvar,
+    transactions: Mutex<HashMap<ThreadId, SQLiteDatabase>>,
+    busy_timeout: Duration,
+}
+
+impl PooledSQLiteDatabase {
+    /// Opens (or creates) the database at `path`, applies any pending migrations
+    /// and opens the pool's connections.
+    pub fn open(path: impl AsRef<Path>, options: PoolOptions) -> Result<Self, DatabaseError> {
+        let path = path.as_ref();
+        let mut first = open_connection(path, options.busy_timeout)?;
+        migrate(&mut first)?;
+
+        let mut idle = vec![SQLiteDatabase::new(first)];
+        for _ in 1..options.size {
+            idle.push(SQLiteDatabase::new(open_connection(
+                path,
+                options.busy_timeout,
+            )?));
+        }
+
+        Ok(Self {
+            idle: Mutex::new(idle),
+            released: Condvar::new(),
+            transactions: Mutex::new(HashMap::new()),
+            busy_timeout: options.busy_timeout,
+        })
+    }
+
+    fn checkout(&self) -> Result<SQLiteDatabase, DatabaseError> {
+        let idle = self.idle.lock().unwrap();
+        let (mut idle, _) = self
+            .released
+            .wait_timeout_while(idle, self.busy_timeout, |idle| idle.is_empty())
+            .unwrap();
+
+        idle.pop()
+            .ok_or(DatabaseError::PoolTimeout(self.busy_timeout))
+    }
+
+    fn release(&self, database: SQLiteDatabase) {
+        self.idle.lock().unwrap().push(database);
+        self.released.notify_one();
+    }
+
+    /// Runs `work` on the calling thread's transaction, or on a free connection
+    /// if it has none.
+    fn with_connection<T>(
+        &self,
+        work: impl FnOnce(&SQLiteDatabase) -> Result<T, DatabaseError>,
+    ) -> Result<T, DatabaseError> {
+        let thread = thread::current().id();
+        // Only this thread ever uses its pinned connection, so it can be taken
+        // out while in use without holding the lock for the whole call.
+        let pinned = self.transactions.lock().unwrap().remove(&thread);
+
+        match pinned {
+            Some(database) => {
+                let result = work(&database);
+                self.transactions.lock().unwrap().insert(thread, database);
+                result
+            }
+            None => {
+                let database = self.checkout()?;
+                let result = work(&database);
+                self.release(database);
+                result
+            }
+        }
+    }
+
+    /// Ends the calling thread's transaction with `end` and returns its connection
+    /// to the pool, rolled back if `end` failed so it never goes back mid-transaction.
+    fn end_transaction(
+        &self,
+        end: impl FnOnce(&SQLiteDatabase) -> Result<(), DatabaseError>,
+    ) -> Result<(), DatabaseError> {
+        let pinned = self
+            .transactions
+            .lock()
+            .unwrap()
+            .remove(&thread::current().id());
+
+        match pinned {
+            Some(database) => {
+                let result = end(&database);
+                if result.is_err() {
+                    let _ = database.rollback();
+                }
+                self.release(database);
+                result
+            }
+            None => self.with_connection(end),
+        }
+    }
+}
+
+/// Rolls back the calling thread's transaction when dropped before `finished` is
+/// set, so a panic in a unit of work doesn't leave its connection pinned and
+/// mid-transaction.
+struct TransactionGuard<'a> {
+    database: &'a PooledSQLiteDatabase,
+    finished: bool,
+}
+
+impl Drop for TransactionGuard<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.database.rollback();
+        }
+    }
+}
+
+fn open_connection(path: &Path, busy_timeout: Duration) -> Result<Connection, DatabaseError> {
+    let conn = Connection::open(path)?;
+    conn.busy_timeout(busy_timeout)?;
+    // WAL lets readers carry on while another connection writes.
+    conn.query_row("PRAGMA journal_mode = WAL", [], |row| {
+        row.get::<_, String>(0)
+    })?;
+
+    Ok(conn)
+}
+
+impl Database for PooledSQLiteDatabase {
+    type Error = DatabaseError;
+
+    fn get_user_including_inactive(&self, user_id: i64) -> Result<Option<User>, Self::Error> {
+        self.with_connection(|db| db.get_user_including_inactive(user_id))
+    }
+
+    fn get_company(&self) -> Result<Option<Company>, Self::Error> {
+        self.with_connection(|db| db.get_company())
+    }
+
+    fn get_companies(&self) -> Result<Vec<Company>, Self::Error> {
+        self.with_connection(|db| db.get_companies())
+    }
+
+    fn insert_company(&self, domain_name: &str) -> Result<i64, Self::Error> {
+        self.with_connection(|db| db.insert_company(domain_name))
+    }
+
+    fn get_employees(&self, domain_name: &str) -> Result<Vec<User>, Self::Error> {
+        self.with_connection(|db| db.get_employees(domain_name))
+    }
+
+    fn count_employees(&self, domain_name: &str) -> Result<i64, Self::Error> {
+        self.with_connection(|db| db.count_employees(domain_name))
+    }
+
+    fn save_company(&self, company: &Company) -> Result<(), Self::Error> {
+        self.with_connection(|db| db.save_company(company))
+    }
+
+    fn adjust_employee_count(&self, company_id: i64, delta: i64) -> Result<(), Self::Error> {
+        self.with_connection(|db| db.adjust_employee_count(company_id, delta))
+    }
+
+    fn insert_user(&self, email: &str, user_type: UserType) -> Result<i64, Self::Error> {
+        self.with_connection(|db| db.insert_user(email, user_type))
+    }
+
+    fn save_user_as(&self, user: &User, actor: &str) -> Result<(), Self::Error> {
+        self.with_connection(|db| db.save_user_as(user, actor))
+    }
+
+    fn get_email_history(&self, user_id: i64) -> Result<Vec<EmailChange>, Self::Error> {
+        self.with_connection(|db| db.get_email_history(user_id))
+    }
+
+    fn find_email_owner(&self, email: &str, at: DateTime<Utc>) -> Result<Option<i64>, Self::Error> {
+        self.with_connection(|db| db.find_email_owner(email, at))
+    }
+
+    fn add_to_outbox(&self, events: &[DomainEvent]) -> Result<(), Self::Error> {
+        self.with_connection(|db| db.add_to_outbox(events))
+    }
+
+    fn get_undelivered_messages(&self, limit: usize) -> Result<Vec<OutboxMessage>, Self::Error> {
+        self.with_connection(|db| db.get_undelivered_messages(limit))
+    }
+
+    fn mark_delivered(&self, message_id: i64) -> Result<(), Self::Error> {
+        self.with_connection(|db| db.mark_delivered(message_id))
+    }
+
+    fn add_dead_letter(
+        &self,
+        envelope: &MessageEnvelope,
+        error: &DispatchError,
+    ) -> Result<(), Self::Error> {
+        self.with_connection(|db| db.add_dead_letter(envelope, error))
+    }
+
+    fn get_dead_letters(&self) -> Result<Vec<DeadLetter>, Self::Error> {
+        self.with_connection(|db| db.get_dead_letters())
+    }
+
+    fn mark_replayed(&self, dead_letter_id: i64) -> Result<(), Self::Error> {
+        self.with_connection(|db| db.mark_replayed(dead_letter_id))
+    }
+
+    fn save_confirmation_token(&self, token: &ConfirmationToken) -> Result<(), Self::Error> {
+        self.with_connection(|db| db.save_confirmation_token(token))
+    }
+
+    fn get_confirmation_token(
+        &self,
+        token: &str,
+    ) -> Result<Option<ConfirmationToken>, Self::Error> {
+        self.with_connection(|db| db.get_confirmation_token(token))
+    }
+
+    fn begin(&self) -> Result<(), Self::Error> {
+        let thread = thread::current().id();
+        if self.transactions.lock().unwrap().contains_key(&thread) {
+            // Let SQLite report the nested transaction.
+            return self.with_connection(|db| db.begin());
+        }
+
+        let database = self.checkout()?;
+        if let Err(e) = database.begin() {
+            self.release(database);
+            return Err(e);
+        }
+        self.transactions.lock().unwrap().insert(thread, database);
+
+        Ok(())
+    }
+
+    fn commit(&self) -> Result<(), Self::Error> {
+        self.end_transaction(|db| db.commit())
+    }
+
+    fn rollback(&self) -> Result<(), Self::Error> {
+        self.end_transaction(|db| db.rollback())
+    }
+
+    fn in_transaction<T, E>(&self, work: impl FnOnce(&Self) -> Result<T, E>) -> Result<T, E>
+    where
+        Self: Sized,
+        E: From<Self::Error>,
+    {
+        self.begin()?;
+        let mut guard = TransactionGuard {
+            database: self,
+            finished: false,
+        };
+
+        let result = work(self);
+        guard.finished = true;
+
+        match result {
+            Ok(value) => {
+                self.commit()?;
+                Ok(value)
+            }
+            Err(e) => {
+                // The original error is more useful to the caller than a failed rollback.
+                let _ = self.rollback();
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::error;
+    use std::fs;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Arc;
+
+    use uuid::Uuid;
+
+    use crate::ch_09::test_helper::test_helper::insert_confirmed_user;
+
+    use super::*;
+
+    struct NoopBus;
+
+    impl Bus for NoopBus {
+        fn send(&self, _: &str) -> Result<(), BusError> {
+            Ok(())
+        }
+    }
+
+    struct NoopLogger;
+
+    impl DomainLogger for NoopLogger {
+        fn user_type_has_changed(&self, _: i64, _: UserType, _: UserType) {}
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn user_controller_is_shareable_between_threads() {
+        assert_send_sync::<UserController<PooledSQLiteDatabase>>();
+    }
+
+    #[test]
+    fn concurrent_email_changes_keep_the_employee_count_consistent(
+    ) -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let path = env::temp_dir().join(format!("pool-{}.db", Uuid::new_v4()));
+        let database = PooledSQLiteDatabase::open(&path, PoolOptions::default())?;
+        database.insert_company("mycorp.com")?;
+        let user_ids = (0..16)
+            .map(|i| {
+                insert_confirmed_user(
+                    &database,
+                    &format!("user{}@gmail.com", i),
+                    UserType::Cusotmer,
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let sut = Arc::new(UserController::new(
+            database,
+            EventDispatcher::new(MessageBus::new(NoopBus), NoopLogger),
+        ));
+
+        // Act
+        let workers: Vec<_> = user_ids
+            .iter()
+            .map(|&user_id| {
+                let sut = Arc::clone(&sut);
+                thread::spawn(move || {
+                    sut.change_email(user_id, &format!("user{}@mycorp.com", user_id))
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap()?;
+        }
+
+        // Assert
+        let company = sut.database.get_company()?.unwrap();
+        assert_eq!(16, company.number_of_employees);
+        assert_eq!(16, sut.database.count_employees("mycorp.com")?);
+        assert!(sut.database.get_undelivered_messages(100)?.is_empty());
+
+        drop(sut);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_employee_count_adjustments_are_not_lost() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let path = env::temp_dir().join(format!("pool-{}.db", Uuid::new_v4()));
+        let database = Arc::new(PooledSQLiteDatabase::open(&path, PoolOptions::default())?);
+        let company_id = database.insert_company("mycorp.com")?;
+
+        // Act
+        let workers: Vec<_> = (0..8)
+            .map(|_| {
+                let database = Arc::clone(&database);
+                thread::spawn(move || -> Result<(), DatabaseError> {
+                    for _ in 0..25 {
+                        database.adjust_employee_count(company_id, 2)?;
+                        database.adjust_employee_count(company_id, -1)?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap()?;
+        }
+
+        // Assert
+        let company = database.get_company()?.unwrap();
+        assert_eq!(200, company.number_of_employees);
+        assert_eq!(400, company.version);
+
+        drop(database);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn panicking_unit_of_work_rolls_back_and_frees_its_connection(
+    ) -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let path = env::temp_dir().join(format!("pool-{}.db", Uuid::new_v4()));
+        let options = PoolOptions {
+            size: 1,
+            busy_timeout: Duration::from_millis(200),
+        };
+        let database = Arc::new(PooledSQLiteDatabase::open(&path, options)?);
+
+        // Act
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            database.in_transaction(|database| -> Result<(), DatabaseError> {
+                database.insert_company("mycorp.com")?;
+                panic!("unit of work failed");
+            })
+        }));
+        let other_thread = Arc::clone(&database);
+        let companies = thread::spawn(move || other_thread.get_companies())
+            .join()
+            .unwrap()?;
+
+        // Assert
+        assert!(result.is_err());
+        assert!(companies.is_empty());
+
+        drop(database);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+        Ok(())
+    }
+}
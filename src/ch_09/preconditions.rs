@@ -0,0 +1,68 @@
+/// Collects every violated rule of an operation instead of stopping at the first,
+/// so a `can_*` check can report all of them to the caller at once.
+#[derive(Debug)]
+pub struct Preconditions<E> {
+    violations: Vec<E>,
+}
+
+impl<E> Default for Preconditions<E> {
+    fn default() -> Self {
+        Self { violations: vec![] }
+    }
+}
+
+impl<E> Preconditions<E> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records `violation` unless `holds` is true.
+    pub fn require(&mut self, holds: bool, violation: impl FnOnce() -> E) -> &mut Self {
+        if !holds {
+            self.violations.push(violation());
+        }
+        self
+    }
+
+    /// Records the error of a check that is already expressed as a `Result`.
+    pub fn check<T>(&mut self, result: Result<T, E>) -> &mut Self {
+        if let Err(violation) = result {
+            self.violations.push(violation);
+        }
+        self
+    }
+
+    pub fn into_result(self) -> Result<(), Vec<E>> {
+        if self.violations.is_empty() {
+            Ok(())
+        } else {
+            Err(self.violations)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_violated_rule_is_reported_in_order() {
+        let mut sut = Preconditions::new();
+
+        sut.require(false, || "first")
+            .require(true, || "skipped")
+            .check::<()>(Err("second"))
+            .check(Ok(1));
+
+        assert_eq!(Err(vec!["first", "second"]), sut.into_result());
+    }
+
+    #[test]
+    fn no_violations_is_ok() {
+        let mut sut = Preconditions::<&str>::new();
+
+        sut.require(true, || "never");
+
+        assert_eq!(Ok(()), sut.into_result());
+    }
+}
@@ -0,0 +1,176 @@
+use serde::Serialize;
+
+use super::outbox::OutboxRelay;
+use super::types::{Database, DomainEvent, EventDispatcher, UserManagementError};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ReconciliationMode {
+    /// Only report companies whose recorded count has drifted.
+    ReportOnly,
+    /// Also correct drifted counts and publish an `EmployeeCountCorrectedEvent`.
+    Correct,
+}
+
+/// A company whose recorded number of employees differs from its users.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct EmployeeCountDrift {
+    pub company_id: i64,
+    pub domain_name: String,
+    pub recorded: i64,
+    pub actual: i64,
+}
+
+/// Recomputes every company's number of employees from its users, counted by
+/// their type as in `Database::get_employees`.
+///
+/// The count is otherwise only maintained by the deltas of individual email changes,
+/// so any write that bypasses them leaves it wrong until this runs.
+pub struct EmployeeCountReconciler<'a, D: Database> {
+    database: &'a D,
+    event_dispatcher: &'a EventDispatcher,
+}
+
+impl<'a, D: Database> EmployeeCountReconciler<'a, D>
+where
+    UserManagementError: From<D::Error>,
+{
+    pub fn new(database: &'a D, event_dispatcher: &'a EventDispatcher) -> Self {
+        Self {
+            database,
+            event_dispatcher,
+        }
+    }
+
+    /// Returns every company whose count had drifted, correcting them if asked to.
+    pub fn reconcile(
+        &self,
+        mode: ReconciliationMode,
+    ) -> Result<Vec<EmployeeCountDrift>, UserManagementError> {
+        let drifts =
+            self.database
+                .in_transaction(|database| -> Result<_, UserManagementError> {
+                    let mut drifts = vec![];
+
+                    for company in database.get_companies()? {
+                        let actual = database.count_employees(&company.domain_name)?;
+                        if actual == company.number_of_employees {
+                            continue;
+                        }
+
+                        drifts.push(EmployeeCountDrift {
+                            company_id: company.id,
+                            domain_name: company.domain_name.clone(),
+                            recorded: company.number_of_employees,
+                            actual,
+                        });
+
+                        if mode == ReconciliationMode::Correct {
+                            let event = DomainEvent::EmployeeCountCorrectedEvent {
+                                company_id: company.id,
+                                old_count: company.number_of_employees,
+                                new_count: actual,
+                            };
+
+                            database.adjust_employee_count(
+                                company.id,
+                                actual - company.number_of_employees,
+                            )?;
+                            database.add_to_outbox(&[event])?;
+                        }
+                    }
+
+                    Ok(drifts)
+                })?;
+
+        OutboxRelay::new(self.database, self.event_dispatcher).relay_pending()?;
+
+        Ok(drifts)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mockall::predicate::function;
+    use std::error;
+
+    use crate::ch_09::bus_message::MessageFormat;
+    use crate::ch_09::sqlite_database::SQLiteDatabase;
+    use crate::ch_09::test_helper::test_helper::{create_company, create_db, create_user};
+    use crate::ch_09::types::*;
+
+    use super::*;
+
+    fn get_db() -> Result<SQLiteDatabase, Box<dyn error::Error>> {
+        let mut db = SQLiteDatabase::new(create_db()?);
+        create_user(&mut db.conn, "one@mycorp.com", UserType::Employee)?;
+        create_user(&mut db.conn, "two@mycorp.com", UserType::Employee)?;
+        create_user(&mut db.conn, "admin@mycorp.com", UserType::Admin)?;
+        create_user(&mut db.conn, "boss@gmail.com", UserType::Admin)?;
+        create_user(&mut db.conn, "contractor@mycorp.com", UserType::Contractor)?;
+        create_user(&mut db.conn, "user@example.com", UserType::Cusotmer)?;
+        create_user(&mut db.conn, "staff@othercorp.com", UserType::Employee)?;
+        create_company(&mut db.conn, "mycorp.com", 5)?;
+        create_company(&mut db.conn, "othercorp.com", 1)?;
+
+        Ok(db)
+    }
+
+    #[test]
+    fn drift_is_reported_without_changing_anything() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let db = get_db()?;
+        let mut bus_mock = MockBus::new();
+        bus_mock.expect_send().times(0);
+        let dispatcher = EventDispatcher::new(MessageBus::new(bus_mock), MockDomainLogger::new());
+        let sut = EmployeeCountReconciler::new(&db, &dispatcher);
+
+        // Act
+        let drifts = sut.reconcile(ReconciliationMode::ReportOnly)?;
+
+        // Assert
+        assert_eq!(
+            vec![EmployeeCountDrift {
+                company_id: 1,
+                domain_name: "mycorp.com".to_owned(),
+                recorded: 5,
+                actual: 4,
+            }],
+            drifts
+        );
+        assert_eq!(5, db.get_company()?.unwrap().number_of_employees);
+
+        Ok(())
+    }
+
+    #[test]
+    fn drifted_counts_are_corrected_and_published() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let db = get_db()?;
+        let mut bus_mock = MockBus::new();
+        bus_mock
+            .expect_send()
+            .with(function(|message: &str| {
+                message.contains(r#""event_type":"EMPLOYEE_COUNT_CORRECTED""#)
+                    && message.contains(r#""old_count":5,"new_count":4"#)
+            }))
+            .times(1)
+            .returning(|_| Ok(()));
+        let dispatcher = EventDispatcher::new(
+            MessageBus::with_format(bus_mock, MessageFormat::Json),
+            MockDomainLogger::new(),
+        );
+        let sut = EmployeeCountReconciler::new(&db, &dispatcher);
+
+        // Act
+        let drifts = sut.reconcile(ReconciliationMode::Correct)?;
+
+        // Assert
+        assert_eq!(1, drifts.len());
+        let company = db.get_company()?.unwrap();
+        assert_eq!(4, company.number_of_employees);
+        assert_eq!(1, company.version);
+        assert!(sut.reconcile(ReconciliationMode::Correct)?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+use std::future::Future;
+use std::thread;
+use std::time::Duration;
+
+/// How often, and how patiently, a failed send is retried.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first. Always at least one.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    /// Each wait is this many times the previous one, up to `max_backoff`.
+    pub multiplier: u32,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            multiplier: 2,
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Tries once and gives up on failure.
+    pub fn no_retries() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// The wait after the given failed attempt, counting from 1.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.saturating_pow(attempt.saturating_sub(1));
+
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+
+    /// Runs `operation` until it succeeds or the attempts run out, returning the
+    /// last error in that case.
+    pub fn run<T, E>(&self, mut operation: impl FnMut() -> Result<T, E>) -> Result<T, E> {
+        let mut attempt = 1;
+
+        loop {
+            match operation() {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt >= self.max_attempts => return Err(e),
+                Err(_) => {
+                    thread::sleep(self.backoff(attempt));
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// `run` for async operations; waits without blocking the runtime.
+    pub async fn run_async<T, E, F>(&self, mut operation: impl FnMut() -> F) -> Result<T, E>
+    where
+        F: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 1;
+
+        loop {
+            match operation().await {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt >= self.max_attempts => return Err(e),
+                Err(_) => {
+                    tokio::time::sleep(self.backoff(attempt)).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_up_to_the_limit() {
+        let sut = RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            multiplier: 3,
+            max_backoff: Duration::from_secs(1),
+        };
+
+        let waits: Vec<_> = (1..=4).map(|attempt| sut.backoff(attempt)).collect();
+
+        assert_eq!(
+            vec![
+                Duration::from_millis(100),
+                Duration::from_millis(300),
+                Duration::from_millis(900),
+                Duration::from_secs(1),
+            ],
+            waits
+        );
+    }
+
+    #[test]
+    fn gives_up_after_the_last_attempt() {
+        let sut = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::ZERO,
+            ..RetryPolicy::default()
+        };
+        let mut attempts = 0;
+
+        let result: Result<(), u32> = sut.run(|| {
+            attempts += 1;
+            Err(attempts)
+        });
+
+        assert_eq!(Err(3), result);
+        assert_eq!(3, attempts);
+    }
+}
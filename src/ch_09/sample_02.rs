@@ -1,79 +1,18 @@
-use super::types::*;
-use rusqlite::Connection;
-struct SQLiteDatabase {
-    pub conn: Connection,
-}
-
-impl Database for SQLiteDatabase {
-    type Error = rusqlite::Error;
-    fn get_user_by_id(&self, user_id: i64) -> Result<Option<User>, Self::Error> {
-        let mut stmt = self
-            .conn
-            .prepare("SELECT id, email, email_confirmed, user_type FROM user WHERE id = ?1")?;
-        let user = stmt
-            .query_map([user_id], |row| {
-                let user_type: String = row.get(3)?;
-                Ok(User {
-                    user_id: row.get(0)?,
-                    email: row.get(1)?,
-                    email_confirmed: row.get(2)?,
-                    domain_events: vec![],
-                    user_type: UserType::from(user_type),
-                })
-            })?
-            .next()
-            .transpose();
-
-        user
-    }
-
-    fn get_company(&self) -> Result<Option<Company>, Self::Error> {
-        let mut stmt = self.conn.prepare("SELECT * from company limit 1")?;
-        let company = stmt
-            .query_map([], |row| {
-                Ok(Company {
-                    id: row.get(0)?,
-                    domain_name: row.get(1)?,
-                    number_of_employees: row.get(2)?,
-                })
-            })?
-            .next()
-            .transpose()?;
-
-        Ok(company)
-    }
-
-    fn save_company(&self, company: &Company) {}
-
-    fn save_user(&self, user: &User) -> Result<(), Self::Error> {
-        self.conn.execute(
-            "UPDATE user SET email = ?1, email_confirmed = ?2, user_type = ?3  WHERE id = ?4",
-            (
-                &user.email,
-                user.email_confirmed,
-                &user.user_type.to_string(),
-                user.user_id,
-            ),
-        )?;
-
-        Ok(())
-    }
-}
-
 #[cfg(test)]
 mod test {
+    use chrono::{DateTime, Utc};
     use mockall::predicate::eq;
     use std::error;
 
+    use crate::ch_09::bus_message::MessageFormat;
     use crate::ch_09::test_helper::test_helper::{
         create_company, create_db, create_user, last_insert_rowid,
     };
 
-    use super::*;
+    use crate::ch_09::{sqlite_database::SQLiteDatabase, types::*};
 
     fn get_db() -> SQLiteDatabase {
-        let mut conn = create_db().unwrap();
-        SQLiteDatabase { conn }
+        SQLiteDatabase::new(create_db().unwrap())
     }
 
     #[test]
@@ -90,7 +29,7 @@ mod test {
                 "Type: USER EMAIL CHANGED; Id: 1; NewEmail: new@example.com",
             ))
             .times(1)
-            .return_once(|_| {});
+            .return_once(|_| Ok(()));
         let message_bus = MessageBus::new(bus_mock);
 
         let mut domain_logger_mock = MockDomainLogger::new();
@@ -112,6 +51,332 @@ mod test {
         assert_eq!("new@example.com", user_from_db.email);
         assert_eq!(UserType::Cusotmer, user_from_db.user_type);
 
+        let company_from_db = sut.database.get_company().unwrap().unwrap();
+        assert_eq!(1, company_from_db.number_of_employees);
+        assert!(sut.database.get_undelivered_messages(10)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn failed_user_save_rolls_back_company_and_sends_nothing() -> Result<(), Box<dyn error::Error>>
+    {
+        // Arrange
+        let mut db = get_db();
+        let user = create_user(&mut db.conn, "user@mycorp.com", UserType::Employee)?;
+        create_company(&mut db.conn, "mycorp.com", 2)?;
+        db.conn.execute_batch(
+            "CREATE TRIGGER fail_user_update BEFORE UPDATE ON user
+             BEGIN SELECT RAISE(FAIL, 'user update failed'); END;",
+        )?;
+
+        let mut bus_mock = MockBus::new();
+        bus_mock.expect_send().times(0);
+        let mut domain_logger_mock = MockDomainLogger::new();
+        domain_logger_mock.expect_user_type_has_changed().times(0);
+
+        let sut = UserController::new(
+            db,
+            EventDispatcher::new(MessageBus::new(bus_mock), domain_logger_mock),
+        );
+
+        // Act
+        let result = sut.change_email(user.user_id, "new@example.com");
+
+        // Assert
+        assert!(result.is_err());
+
+        let company_from_db = sut.database.get_company()?.unwrap();
+        assert_eq!(2, company_from_db.number_of_employees);
+        assert_eq!(0, company_from_db.version);
+
+        let user_from_db = sut.database.get_user_by_id(user.user_id)?.unwrap();
+        assert_eq!("user@mycorp.com", user_from_db.email);
+        assert!(sut.database.get_undelivered_messages(10)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn changing_email_reports_why_it_failed() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let mut db = get_db();
+        let mut unconfirmed = create_user(&mut db.conn, "user@mycorp.com", UserType::Employee)?;
+        unconfirmed.email_confirmed = false;
+        db.save_user(&unconfirmed)?;
+        let confirmed = create_user(&mut db.conn, "other@mycorp.com", UserType::Employee)?;
+
+        let mut bus_mock = MockBus::new();
+        bus_mock.expect_send().times(0);
+        let mut sut = UserController::new(
+            db,
+            EventDispatcher::new(MessageBus::new(bus_mock), MockDomainLogger::new()),
+        );
+
+        // Act & Assert
+        assert!(matches!(
+            sut.change_email(42, "new@example.com"),
+            Err(UserManagementError::UserNotFound(42))
+        ));
+        assert!(matches!(
+            sut.change_email(confirmed.user_id, "new@example.com"),
+            Err(UserManagementError::CompanyNotFound)
+        ));
+
+        create_company(&mut sut.database.conn, "mycorp.com", 2)?;
+        assert!(matches!(
+            sut.change_email(unconfirmed.user_id, "new@example.com"),
+            Err(UserManagementError::EmailNotConfirmed(id)) if id == unconfirmed.user_id
+        ));
+        assert!(matches!(
+            sut.change_email(confirmed.user_id, "not-an-email"),
+            Err(UserManagementError::InvalidEmail(email)) if email == "not-an-email"
+        ));
+
+        let result = sut.change_email(unconfirmed.user_id, "not-an-email");
+        let Err(UserManagementError::PreconditionsFailed(violations)) = result else {
+            panic!("expected every violation to be reported, got {:?}", result);
+        };
+        assert!(matches!(
+            violations.as_slice(),
+            [
+                UserManagementError::EmailNotConfirmed(_),
+                UserManagementError::InvalidEmail(_)
+            ]
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn loading_a_user_with_an_unknown_type_fails() -> Result<(), Box<dyn error::Error>> {
+        let db = get_db();
+        db.conn.execute_batch(
+            "PRAGMA ignore_check_constraints = ON;
+             INSERT INTO user (email, user_type) VALUES ('user@mycorp.com', 'EMPLOYE');",
+        )?;
+
+        let result = db.get_user_by_id(1);
+
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn registering_a_corporate_email_adds_an_employee() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let mut db = get_db();
+        create_company(&mut db.conn, "mycorp.com", 0)?;
+
+        let mut bus_mock = MockBus::new();
+        bus_mock
+            .expect_send()
+            .withf(|message| message.contains("\"event_type\":\"USER_REGISTERED\""))
+            .times(1)
+            .returning(|_| Ok(()));
+        bus_mock
+            .expect_send()
+            .withf(|message| message.contains("\"event_type\":\"EMAIL_CONFIRMATION_REQUESTED\""))
+            .times(1)
+            .returning(|_| Ok(()));
+        let message_bus = MessageBus::with_format(bus_mock, MessageFormat::Json);
+
+        let sut = UserController::new(
+            db,
+            EventDispatcher::new(message_bus, MockDomainLogger::new()),
+        );
+
+        // Act
+        let user_id = sut.register_user("new@mycorp.com")?;
+
+        // Assert
+        let user_from_db = sut.database.get_user_by_id(user_id)?.unwrap();
+        assert_eq!("new@mycorp.com", user_from_db.email);
+        assert_eq!(UserType::Employee, user_from_db.user_type);
+        assert!(!user_from_db.email_confirmed);
+
+        let company_from_db = sut.database.get_company()?.unwrap();
+        assert_eq!(1, company_from_db.number_of_employees);
+
+        Ok(())
+    }
+
+    #[test]
+    fn inserted_users_start_unconfirmed() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let db = get_db();
+
+        // Act
+        let user_id = db.insert_user("new@mycorp.com", UserType::Employee)?;
+
+        // Assert
+        let user_from_db = db.get_user_by_id(user_id)?.unwrap();
+        assert!(!user_from_db.email_confirmed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn registering_a_taken_email_creates_nothing() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let mut db = get_db();
+        create_user(&mut db.conn, "user@mycorp.com", UserType::Employee)?;
+        create_company(&mut db.conn, "mycorp.com", 1)?;
+
+        let sut = UserController::new(
+            db,
+            EventDispatcher::new(MessageBus::new(MockBus::new()), MockDomainLogger::new()),
+        );
+
+        // Act
+        let result = sut.register_user("user@mycorp.com");
+
+        // Assert
+        assert!(matches!(
+            result,
+            Err(UserManagementError::EmailAlreadyInUse(email)) if email == "user@mycorp.com"
+        ));
+        assert!(sut.database.get_user_by_id(2)?.is_none());
+        let company_from_db = sut.database.get_company()?.unwrap();
+        assert_eq!(1, company_from_db.number_of_employees);
+        assert!(sut.database.get_undelivered_messages(10)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn deleted_employee_is_hidden_and_no_longer_counted() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let mut db = get_db();
+        let user = create_user(&mut db.conn, "user@mycorp.com", UserType::Employee)?;
+        create_company(&mut db.conn, "mycorp.com", 1)?;
+
+        let mut bus_mock = MockBus::new();
+        bus_mock
+            .expect_send()
+            .withf(|message| message.contains("\"event_type\":\"USER_DELETED\""))
+            .times(1)
+            .returning(|_| Ok(()));
+        let message_bus = MessageBus::with_format(bus_mock, MessageFormat::Json);
+
+        let sut = UserController::new(
+            db,
+            EventDispatcher::new(message_bus, MockDomainLogger::new()),
+        );
+
+        // Act
+        sut.delete_user(user.user_id)?;
+
+        // Assert
+        assert!(sut.database.get_user_by_id(user.user_id)?.is_none());
+        let user_from_db = sut
+            .database
+            .get_user_including_inactive(user.user_id)?
+            .unwrap();
+        assert_eq!(UserStatus::Deleted, user_from_db.status);
+        let deleted_at: Option<DateTime<Utc>> = sut.database.conn.query_row(
+            "SELECT deleted_at FROM user WHERE id = ?1",
+            [user.user_id],
+            |row| row.get(0),
+        )?;
+        assert!(deleted_at.is_some());
+
+        let company_from_db = sut.database.get_company()?.unwrap();
+        assert_eq!(0, company_from_db.number_of_employees);
+        assert!(sut.database.get_employees("mycorp.com")?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn failing_to_relay_after_commit_keeps_the_change_and_the_event(
+    ) -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let mut db = get_db();
+        let user = create_user(&mut db.conn, "user@mycorp.com", UserType::Employee)?;
+        create_company(&mut db.conn, "mycorp.com", 1)?;
+        db.conn.execute_batch(
+            "CREATE TRIGGER outbox_down BEFORE UPDATE ON outbox
+             BEGIN SELECT RAISE(ABORT, 'outbox is down'); END;",
+        )?;
+
+        let mut bus_mock = MockBus::new();
+        bus_mock.expect_send().returning(|_| Ok(()));
+        let mut domain_logger_mock = MockDomainLogger::new();
+        domain_logger_mock
+            .expect_user_type_has_changed()
+            .returning(|_, _, _| {});
+
+        let sut = UserController::new(
+            db,
+            EventDispatcher::new(MessageBus::new(bus_mock), domain_logger_mock),
+        );
+
+        // Act
+        let result = sut.change_email(user.user_id, "new@gmail.com");
+
+        // Assert
+        assert!(result.is_ok());
+        let user_from_db = sut.database.get_user_by_id(user.user_id)?.unwrap();
+        assert_eq!("new@gmail.com", user_from_db.email);
+        assert_eq!(2, sut.database.get_undelivered_messages(10)?.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn employee_count_adjustments_cannot_go_negative() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let mut db = get_db();
+        create_company(&mut db.conn, "mycorp.com", 1)?;
+
+        // Act
+        db.adjust_employee_count(1, -1)?;
+        let result = db.adjust_employee_count(1, -1);
+
+        // Assert
+        assert!(matches!(
+            result,
+            Err(DatabaseError::EmployeeCountUnderflow {
+                company_id: 1,
+                number_of_employees: 0,
+                delta: -1
+            })
+        ));
+        let company_from_db = db.get_company()?.unwrap();
+        assert_eq!(0, company_from_db.number_of_employees);
+        assert_eq!(1, company_from_db.version);
+
+        Ok(())
+    }
+
+    #[test]
+    fn saving_a_stale_company_fails_with_conflict() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let mut db = get_db();
+        create_company(&mut db.conn, "mycorp.com", 2)?;
+        let mut first = db.get_company()?.unwrap();
+        let mut second = db.get_company()?.unwrap();
+
+        // Act
+        first.number_of_employees += 1;
+        db.save_company(&first)?;
+        second.number_of_employees -= 1;
+        let result = db.save_company(&second);
+
+        // Assert
+        assert!(matches!(
+            result,
+            Err(DatabaseError::ConcurrencyConflict {
+                company_id: 1,
+                expected_version: 0
+            })
+        ));
+        let company_from_db = db.get_company()?.unwrap();
+        assert_eq!(3, company_from_db.number_of_employees);
+        assert_eq!(1, company_from_db.version);
+
         Ok(())
     }
 }
@@ -0,0 +1,475 @@
+use super::bus_message::MessageEnvelope;
+use super::dead_letter::DeadLetter;
+use super::email_confirmation::ConfirmationToken;
+use super::email_history::EmailChange;
+use super::event_store::EventStore;
+use super::subscribers::DispatchError;
+use super::types::*;
+use crate::migrations::migrate;
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, OptionalExtension, Row};
+use std::path::Path;
+use uuid::Uuid;
+
+pub struct SQLiteDatabase {
+    pub conn: Connection,
+    event_store: Option<EventStore>,
+}
+
+impl SQLiteDatabase {
+    pub fn new(conn: Connection) -> Self {
+        Self {
+            conn,
+            event_store: None,
+        }
+    }
+
+    /// Records every saved user change in `event_store` and loads users by replaying
+    /// their events. The `user` table is still kept up to date as a read model.
+    pub fn with_event_sourcing(conn: Connection, event_store: EventStore) -> Self {
+        Self {
+            conn,
+            event_store: Some(event_store),
+        }
+    }
+
+    /// Opens (or creates) the database at `path` and applies any pending migrations.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, DatabaseError> {
+        let mut conn = Connection::open(path)?;
+        migrate(&mut conn)?;
+
+        Ok(Self::new(conn))
+    }
+
+    fn get_user_row(&self, user_id: i64) -> Result<Option<User>, DatabaseError> {
+        let user = self
+            .conn
+            .query_row(
+                &format!("SELECT {} FROM user WHERE id = ?1", USER_COLUMNS),
+                [user_id],
+                user_from_row,
+            )
+            .optional()?;
+
+        Ok(user)
+    }
+}
+
+impl Database for SQLiteDatabase {
+    type Error = DatabaseError;
+    fn get_user_including_inactive(&self, user_id: i64) -> Result<Option<User>, Self::Error> {
+        if let Some(event_store) = &self.event_store {
+            if let Some(user) = event_store.load(&self.conn, user_id)? {
+                return Ok(Some(user));
+            }
+        }
+
+        self.get_user_row(user_id)
+    }
+
+    fn get_company(&self) -> Result<Option<Company>, Self::Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, domain, number_of_employees, version FROM company ORDER BY id LIMIT 1",
+        )?;
+        let company = stmt
+            .query_map([], |row| {
+                Ok(Company {
+                    id: row.get(0)?,
+                    domain_name: row.get(1)?,
+                    number_of_employees: row.get(2)?,
+                    version: row.get(3)?,
+                })
+            })?
+            .next()
+            .transpose()?;
+
+        Ok(company)
+    }
+
+    fn get_companies(&self) -> Result<Vec<Company>, Self::Error> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id, domain, number_of_employees, version FROM company ORDER BY id")?;
+        let companies = stmt
+            .query_map([], |row| {
+                Ok(Company {
+                    id: row.get(0)?,
+                    domain_name: row.get(1)?,
+                    number_of_employees: row.get(2)?,
+                    version: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(companies)
+    }
+
+    fn insert_company(&self, domain_name: &str) -> Result<i64, Self::Error> {
+        self.conn.execute(
+            "INSERT INTO company (domain, number_of_employees) VALUES (?1, 0)",
+            [domain_name],
+        )?;
+
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    fn get_employees(&self, domain_name: &str) -> Result<Vec<User>, Self::Error> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM user
+             WHERE deactivated_at IS NULL AND deleted_at IS NULL
+               AND (substr(email, instr(email, '@') + 1) = ?1
+                    OR (?1 = (SELECT domain FROM company ORDER BY id LIMIT 1)
+                        AND substr(email, instr(email, '@') + 1)
+                            NOT IN (SELECT domain FROM company)))
+             ORDER BY id",
+            USER_COLUMNS
+        ))?;
+        let users = stmt
+            .query_map([domain_name], user_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(users
+            .into_iter()
+            .filter(|user| user.user_type.counts_toward_employees())
+            .collect())
+    }
+
+    fn save_company(&self, company: &Company) -> Result<(), Self::Error> {
+        let updated = self.conn.execute(
+            "UPDATE company SET domain = ?1, number_of_employees = ?2, version = version + 1
+             WHERE id = ?3 AND version = ?4",
+            (
+                &company.domain_name,
+                company.number_of_employees,
+                company.id,
+                company.version,
+            ),
+        )?;
+
+        if updated == 0 {
+            return Err(DatabaseError::ConcurrencyConflict {
+                company_id: company.id,
+                expected_version: company.version,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn adjust_employee_count(&self, company_id: i64, delta: i64) -> Result<(), Self::Error> {
+        let updated = self.conn.execute(
+            "UPDATE company
+             SET number_of_employees = number_of_employees + ?1, version = version + 1
+             WHERE id = ?2 AND number_of_employees + ?1 >= 0",
+            (delta, company_id),
+        )?;
+
+        if updated == 0 {
+            let number_of_employees = self
+                .conn
+                .query_row(
+                    "SELECT number_of_employees FROM company WHERE id = ?1",
+                    [company_id],
+                    |row| row.get(0),
+                )
+                .optional()?
+                .ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+
+            return Err(DatabaseError::EmployeeCountUnderflow {
+                company_id,
+                number_of_employees,
+                delta,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn insert_user(&self, email: &str, user_type: UserType) -> Result<i64, Self::Error> {
+        self.conn.execute(
+            "INSERT INTO user (email, user_type, email_confirmed, created_at)
+             VALUES (?1, ?2, FALSE, ?3)",
+            (email, user_type.to_string(), Utc::now()),
+        )?;
+
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    fn save_user_as(&self, user: &User, actor: &str) -> Result<(), Self::Error> {
+        if let Some(stored) = self.get_user_row(user.user_id)? {
+            if let Some(event_store) = &self.event_store {
+                if !user.domain_events.is_empty() {
+                    event_store.append(&self.conn, &stored, &user.domain_events)?;
+                }
+            }
+
+            if stored.email != user.email {
+                self.conn.execute(
+                    "INSERT INTO email_history
+                        (user_id, old_email, new_email, old_type, new_type, changed_at, actor)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    (
+                        user.user_id,
+                        &stored.email,
+                        &user.email,
+                        stored.user_type.to_string(),
+                        user.user_type.to_string(),
+                        Utc::now(),
+                        actor,
+                    ),
+                )?;
+            }
+        }
+
+        // A status keeps the time it was first entered; leaving it clears it.
+        self.conn.execute(
+            "UPDATE user SET email = ?1, email_confirmed = ?2, user_type = ?3,
+                deactivated_at = CASE WHEN ?4 THEN COALESCE(deactivated_at, ?6) END,
+                deleted_at = CASE WHEN ?5 THEN COALESCE(deleted_at, ?6) END
+             WHERE id = ?7",
+            (
+                &user.email,
+                user.email_confirmed,
+                &user.user_type.to_string(),
+                user.status == UserStatus::Deactivated,
+                user.status == UserStatus::Deleted,
+                Utc::now(),
+                user.user_id,
+            ),
+        )?;
+
+        Ok(())
+    }
+
+    fn get_email_history(&self, user_id: i64) -> Result<Vec<EmailChange>, Self::Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT user_id, old_email, new_email, old_type, new_type, changed_at, actor
+             FROM email_history WHERE user_id = ?1 ORDER BY id",
+        )?;
+        let history = stmt
+            .query_map([user_id], |row| {
+                Ok(EmailChange {
+                    user_id: row.get(0)?,
+                    old_email: row.get(1)?,
+                    new_email: row.get(2)?,
+                    old_type: row.get(3)?,
+                    new_type: row.get(4)?,
+                    changed_at: row.get(5)?,
+                    actor: row.get(6)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(history)
+    }
+
+    fn find_email_owner(&self, email: &str, at: DateTime<Utc>) -> Result<Option<i64>, Self::Error> {
+        // A user's address at `at` is the one set by their last change before it;
+        // failing that, the one their first later change replaced; failing that,
+        // their current one. Only users that existed at `at` count: created by then
+        // (or before creation was recorded) and not yet deleted. A deactivated user
+        // keeps their address. Nothing stops two users sharing an address, in which
+        // case the earliest created one is the owner.
+        let owner = self
+            .conn
+            .query_row(
+                "SELECT id FROM user
+                 WHERE (created_at IS NULL OR created_at <= ?2)
+                   AND (deleted_at IS NULL OR deleted_at > ?2)
+                   AND COALESCE(
+                       (SELECT new_email FROM email_history
+                        WHERE user_id = user.id AND changed_at <= ?2
+                        ORDER BY id DESC LIMIT 1),
+                       (SELECT old_email FROM email_history
+                        WHERE user_id = user.id AND changed_at > ?2
+                        ORDER BY id LIMIT 1),
+                       email
+                   ) = ?1
+                 ORDER BY id
+                 LIMIT 1",
+                (email, at),
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(owner)
+    }
+
+    fn add_to_outbox(&self, events: &[DomainEvent]) -> Result<(), Self::Error> {
+        let mut stmt = self
+            .conn
+            .prepare("INSERT INTO outbox (envelope) VALUES (?1)")?;
+
+        for event in events {
+            let envelope = MessageEnvelope::new(event.clone(), Uuid::new_v4(), Utc::now());
+            stmt.execute([envelope.to_json()])?;
+        }
+
+        Ok(())
+    }
+
+    fn get_undelivered_messages(&self, limit: usize) -> Result<Vec<OutboxMessage>, Self::Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, envelope FROM outbox
+             WHERE delivered_at IS NULL ORDER BY id LIMIT ?1",
+        )?;
+        let messages = stmt
+            .query_map([limit as i64], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })?
+            .map(|row| {
+                let (id, envelope) = row?;
+                Ok(OutboxMessage {
+                    id,
+                    envelope: serde_json::from_str(&envelope)?,
+                })
+            })
+            .collect::<Result<Vec<_>, DatabaseError>>()?;
+
+        Ok(messages)
+    }
+
+    fn mark_delivered(&self, message_id: i64) -> Result<(), Self::Error> {
+        self.conn.execute(
+            "UPDATE outbox SET delivered_at = CURRENT_TIMESTAMP WHERE id = ?1",
+            [message_id],
+        )?;
+
+        Ok(())
+    }
+
+    fn add_dead_letter(
+        &self,
+        envelope: &MessageEnvelope,
+        error: &DispatchError,
+    ) -> Result<(), Self::Error> {
+        self.conn.execute(
+            "INSERT INTO dead_letter (envelope, error, subscribers, failed_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            (
+                envelope.to_json(),
+                error.to_string(),
+                serde_json::to_string(&error.subscribers())?,
+                Utc::now(),
+            ),
+        )?;
+
+        Ok(())
+    }
+
+    fn get_dead_letters(&self) -> Result<Vec<DeadLetter>, Self::Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, envelope, error, failed_at, subscribers FROM dead_letter
+             WHERE replayed_at IS NULL ORDER BY id",
+        )?;
+        let dead_letters = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                ))
+            })?
+            .map(|row| {
+                let (id, envelope, error, failed_at, subscribers) = row?;
+                Ok(DeadLetter {
+                    id,
+                    envelope: serde_json::from_str(&envelope)?,
+                    error,
+                    failed_at,
+                    subscribers: match subscribers {
+                        Some(subscribers) => serde_json::from_str(&subscribers)?,
+                        None => vec![],
+                    },
+                })
+            })
+            .collect::<Result<Vec<_>, DatabaseError>>()?;
+
+        Ok(dead_letters)
+    }
+
+    fn mark_replayed(&self, dead_letter_id: i64) -> Result<(), Self::Error> {
+        self.conn.execute(
+            "UPDATE dead_letter SET replayed_at = ?1 WHERE id = ?2",
+            (Utc::now(), dead_letter_id),
+        )?;
+
+        Ok(())
+    }
+
+    fn save_confirmation_token(&self, token: &ConfirmationToken) -> Result<(), Self::Error> {
+        self.conn.execute(
+            "INSERT INTO email_confirmation_token (token, user_id, expires_at, used_at)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (token) DO UPDATE SET used_at = excluded.used_at",
+            (&token.token, token.user_id, token.expires_at, token.used_at),
+        )?;
+
+        Ok(())
+    }
+
+    fn get_confirmation_token(
+        &self,
+        token: &str,
+    ) -> Result<Option<ConfirmationToken>, Self::Error> {
+        let token = self
+            .conn
+            .query_row(
+                "SELECT token, user_id, expires_at, used_at FROM email_confirmation_token
+                 WHERE token = ?1",
+                [token],
+                |row| {
+                    Ok(ConfirmationToken {
+                        token: row.get(0)?,
+                        user_id: row.get(1)?,
+                        expires_at: row.get(2)?,
+                        used_at: row.get(3)?,
+                    })
+                },
+            )
+            .optional()?;
+
+        Ok(token)
+    }
+
+    fn begin(&self) -> Result<(), Self::Error> {
+        // IMMEDIATE takes the write lock up front, so a concurrent writer fails
+        // here instead of after we've read rows it is about to change.
+        self.conn.execute_batch("BEGIN IMMEDIATE")?;
+        Ok(())
+    }
+
+    fn commit(&self) -> Result<(), Self::Error> {
+        self.conn.execute_batch("COMMIT")?;
+        Ok(())
+    }
+
+    fn rollback(&self) -> Result<(), Self::Error> {
+        self.conn.execute_batch("ROLLBACK")?;
+        Ok(())
+    }
+}
+
+const USER_COLUMNS: &str = "id, email, email_confirmed, user_type, deactivated_at, deleted_at";
+
+/// Reads a user selected with `USER_COLUMNS`.
+fn user_from_row(row: &Row) -> rusqlite::Result<User> {
+    let deactivated_at: Option<DateTime<Utc>> = row.get(4)?;
+    let deleted_at: Option<DateTime<Utc>> = row.get(5)?;
+    let status = match (deactivated_at, deleted_at) {
+        (_, Some(_)) => UserStatus::Deleted,
+        (Some(_), None) => UserStatus::Deactivated,
+        (None, None) => UserStatus::Active,
+    };
+
+    Ok(User {
+        user_id: row.get(0)?,
+        email: row.get(1)?,
+        email_confirmed: row.get(2)?,
+        domain_events: vec![],
+        user_type: row.get(3)?,
+        status,
+    })
+}
@@ -0,0 +1,244 @@
+use super::bus_message::MessageEnvelope;
+use super::types::{Bus, DomainEvent, DomainLogger, MessageBus};
+
+pub type HandlerError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Reacts to dispatched domain events.
+pub trait EventHandler: Send + Sync {
+    fn handle(&self, envelope: &MessageEnvelope) -> Result<(), HandlerError>;
+}
+
+/// The kinds of `DomainEvent`, for subscribing to one of them.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum EventKind {
+    EmailChanged,
+    UserTypeChanged,
+    EmailConfirmationRequested,
+    EmailConfirmed,
+    UserRegistered,
+    UserDeactivated,
+    UserReactivated,
+    UserDeleted,
+    EmployeeCountCorrected,
+}
+
+impl DomainEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            DomainEvent::EmailChangeEvent { .. } => EventKind::EmailChanged,
+            DomainEvent::UserTypeChangeEvent { .. } => EventKind::UserTypeChanged,
+            DomainEvent::EmailConfirmationRequestedEvent { .. } => {
+                EventKind::EmailConfirmationRequested
+            }
+            DomainEvent::EmailConfirmedEvent { .. } => EventKind::EmailConfirmed,
+            DomainEvent::UserRegisteredEvent { .. } => EventKind::UserRegistered,
+            DomainEvent::UserDeactivatedEvent { .. } => EventKind::UserDeactivated,
+            DomainEvent::UserReactivatedEvent { .. } => EventKind::UserReactivated,
+            DomainEvent::UserDeletedEvent { .. } => EventKind::UserDeleted,
+            DomainEvent::EmployeeCountCorrectedEvent { .. } => EventKind::EmployeeCountCorrected,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct HandlerFailure {
+    pub subscriber: String,
+    pub error: HandlerError,
+}
+
+/// Every handler that failed for an event. The others still ran.
+#[derive(Debug, thiserror::Error)]
+#[error("{}", .failures.iter().map(|f| format!("{}: {}", f.subscriber, f.error)).collect::<Vec<_>>().join("; "))]
+pub struct DispatchError {
+    pub failures: Vec<HandlerFailure>,
+}
+
+impl DispatchError {
+    /// The names of the subscribers that failed, in the order they ran.
+    pub fn subscribers(&self) -> Vec<String> {
+        self.failures.iter().map(|f| f.subscriber.clone()).collect()
+    }
+}
+
+struct Subscription {
+    name: String,
+    kind: Option<EventKind>,
+    handler: Box<dyn EventHandler>,
+}
+
+/// Handlers subscribed to one kind of event or to all of them, invoked in the
+/// order they subscribed.
+#[derive(Default)]
+pub struct SubscriberRegistry {
+    subscriptions: Vec<Subscription>,
+}
+
+impl SubscriberRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(
+        &mut self,
+        name: impl Into<String>,
+        kind: EventKind,
+        handler: impl EventHandler + 'static,
+    ) {
+        self.add(name.into(), Some(kind), Box::new(handler));
+    }
+
+    pub fn subscribe_all(&mut self, name: impl Into<String>, handler: impl EventHandler + 'static) {
+        self.add(name.into(), None, Box::new(handler));
+    }
+
+    fn add(&mut self, name: String, kind: Option<EventKind>, handler: Box<dyn EventHandler>) {
+        self.subscriptions.push(Subscription {
+            name,
+            kind,
+            handler,
+        });
+    }
+
+    /// Hands `envelope` to every matching handler, even after one has failed.
+    pub fn publish(&self, envelope: &MessageEnvelope) -> Result<(), DispatchError> {
+        self.publish_where(envelope, |_| true)
+    }
+
+    /// Hands `envelope` to the matching handlers subscribed under one of `names`,
+    /// e.g. to retry only those that failed.
+    pub fn publish_to(
+        &self,
+        envelope: &MessageEnvelope,
+        names: &[String],
+    ) -> Result<(), DispatchError> {
+        self.publish_where(envelope, |subscription| names.contains(&subscription.name))
+    }
+
+    fn publish_where(
+        &self,
+        envelope: &MessageEnvelope,
+        selected: impl Fn(&Subscription) -> bool,
+    ) -> Result<(), DispatchError> {
+        let kind = envelope.event.kind();
+        let failures: Vec<_> = self
+            .subscriptions
+            .iter()
+            .filter(|subscription| subscription.kind.is_none_or(|k| k == kind))
+            .filter(|subscription| selected(subscription))
+            .filter_map(|subscription| {
+                subscription
+                    .handler
+                    .handle(envelope)
+                    .err()
+                    .map(|error| HandlerFailure {
+                        subscriber: subscription.name.clone(),
+                        error,
+                    })
+            })
+            .collect();
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(DispatchError { failures })
+        }
+    }
+}
+
+impl<B: Bus + Send + Sync> EventHandler for MessageBus<B> {
+    fn handle(&self, envelope: &MessageEnvelope) -> Result<(), HandlerError> {
+        Ok(self.publish(envelope)?)
+    }
+}
+
+/// Subscribes a `DomainLogger` to user type changes.
+pub struct DomainLoggerHandler<L: DomainLogger>(pub L);
+
+impl<L: DomainLogger + Send + Sync> EventHandler for DomainLoggerHandler<L> {
+    fn handle(&self, envelope: &MessageEnvelope) -> Result<(), HandlerError> {
+        if let DomainEvent::UserTypeChangeEvent {
+            user_id,
+            old_type,
+            new_type,
+        } = &envelope.event
+        {
+            self.0.user_type_has_changed(*user_id, *old_type, *new_type);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::ch_09::types::UserType;
+
+    struct Recorder {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<String>>>,
+        fails: bool,
+    }
+
+    impl EventHandler for Recorder {
+        fn handle(&self, envelope: &MessageEnvelope) -> Result<(), HandlerError> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("{} {:?}", self.name, envelope.event.kind()));
+            if self.fails {
+                return Err("unavailable".into());
+            }
+            Ok(())
+        }
+    }
+
+    fn envelope(event: DomainEvent) -> MessageEnvelope {
+        MessageEnvelope::new(event, Uuid::new_v4(), Utc::now())
+    }
+
+    #[test]
+    fn matching_handlers_run_in_order_despite_failures() {
+        // Arrange
+        let calls = Arc::new(Mutex::new(vec![]));
+        let recorder = |name, fails| Recorder {
+            name,
+            calls: calls.clone(),
+            fails,
+        };
+        let mut sut = SubscriberRegistry::new();
+        sut.subscribe("crm", EventKind::EmailChanged, recorder("crm", true));
+        sut.subscribe("hr", EventKind::UserTypeChanged, recorder("hr", false));
+        sut.subscribe_all("audit", recorder("audit", false));
+
+        // Act
+        let email_changed = sut.publish(&envelope(DomainEvent::EmailChangeEvent {
+            user_id: 1,
+            new_email: "new@example.com".to_owned(),
+        }));
+        let type_changed = sut.publish(&envelope(DomainEvent::UserTypeChangeEvent {
+            user_id: 1,
+            old_type: UserType::Employee,
+            new_type: UserType::Cusotmer,
+        }));
+
+        // Assert
+        let error = email_changed.unwrap_err();
+        assert_eq!("crm: unavailable", error.to_string());
+        assert!(type_changed.is_ok());
+        assert_eq!(
+            vec![
+                "crm EmailChanged",
+                "audit EmailChanged",
+                "hr UserTypeChanged",
+                "audit UserTypeChanged",
+            ],
+            *calls.lock().unwrap()
+        );
+    }
+}
@@ -1,36 +1,31 @@
 #[cfg(test)]
 pub mod test_helper {
-    use crate::ch_09::types::{Company, User, UserType};
+    use crate::ch_09::pooled_database::{PoolOptions, PooledSQLiteDatabase};
+    use crate::ch_09::types::{Company, Database, DatabaseError, User, UserStatus, UserType};
+    use crate::migrations::migrate;
     use rusqlite::{Connection, Result};
 
     pub fn create_db() -> Result<Connection> {
-        let conn = Connection::open_in_memory()?;
-
-        conn.execute(
-            "CREATE TABLE user (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                email TEXT NOT NULL,
-                email_confirmed INT NOT NULL DEFAULT TRUE,
-                user_type TEXT collate BINARY NOT NULL,
-                CHECK (user_type = 'CUSTOMER' OR user_type = 'EMPLOYEE')
-            )",
-            (),
-        )?;
-
-        conn.execute(
-            "CREATE TABLE company (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                domain TEXT NOT NULL,
-                number_of_employees INTEGER NOT NULl
-            )",
-            (),
-        )?;
+        let mut conn = Connection::open_in_memory()?;
+        migrate(&mut conn)?;
 
         println!("{}", rusqlite::version());
 
         Ok(conn)
     }
 
+    /// A pool of a single connection to an in-memory database, for code that
+    /// needs a `Database` shared between threads.
+    pub fn create_pooled_db() -> std::result::Result<PooledSQLiteDatabase, DatabaseError> {
+        PooledSQLiteDatabase::open(
+            ":memory:",
+            PoolOptions {
+                size: 1,
+                ..PoolOptions::default()
+            },
+        )
+    }
+
     pub fn create_user(
         conn: &mut Connection,
         email: impl Into<String>,
@@ -42,6 +37,7 @@ pub mod test_helper {
             domain_events: vec![],
             user_id: 0,
             user_type,
+            status: UserStatus::Active,
         };
 
         let tx = conn.transaction()?;
@@ -57,6 +53,23 @@ pub mod test_helper {
         Ok(user)
     }
 
+    /// Inserts a user through `database` and confirms their email, as most use
+    /// cases expect.
+    pub fn insert_confirmed_user<D: Database>(
+        database: &D,
+        email: &str,
+        user_type: UserType,
+    ) -> std::result::Result<i64, D::Error> {
+        let user_id = database.insert_user(email, user_type)?;
+        let mut user = database
+            .get_user_by_id(user_id)?
+            .expect("the user was just inserted");
+        user.email_confirmed = true;
+        database.save_user(&user)?;
+
+        Ok(user_id)
+    }
+
     pub fn create_company(
         conn: &mut Connection,
         domain: impl Into<String>,
@@ -66,6 +79,7 @@ pub mod test_helper {
             id: 0,
             domain_name: domain.into(),
             number_of_employees,
+            version: 0,
         };
 
         let tx = conn.transaction()?;
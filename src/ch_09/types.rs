@@ -1,35 +1,96 @@
-#[derive(Debug)]
+use chrono::{DateTime, Utc};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ValueRef};
+use serde::{Deserialize, Serialize, Serializer};
+use std::str::FromStr;
+use uuid::Uuid;
+
+use super::bus_message::{MessageEnvelope, MessageFormat};
+use super::dead_letter::DeadLetter;
+use super::email_confirmation::{ConfirmationToken, TokenError};
+use super::email_history::{EmailChange, SYSTEM_ACTOR};
+use super::event_store::UserSnapshot;
+use super::outbox::OutboxRelay;
+use super::preconditions::Preconditions;
+use super::retry::RetryPolicy;
+use super::subscribers::{DispatchError, DomainLoggerHandler, EventKind, SubscriberRegistry};
+
+#[derive(Debug, Clone)]
 pub struct User {
     pub user_id: i64,
     pub email: String,
     pub email_confirmed: bool,
     pub domain_events: Vec<DomainEvent>,
     pub user_type: UserType,
+    pub status: UserStatus,
 }
 
 impl User {
-    pub fn can_change_email(&self) -> bool {
-        self.email_confirmed
+    /// A newly registered user of `user_type`, who has just been stored as
+    /// `user_id`. An employee is added to the company's count. The email stays
+    /// unconfirmed until the user confirms it.
+    pub fn register(
+        user_id: i64,
+        email: &str,
+        user_type: UserType,
+        company: &mut Company,
+    ) -> Result<User, UserManagementError> {
+        company.change_number_of_employees(i64::from(user_type.counts_toward_employees()))?;
+
+        Ok(User {
+            user_id,
+            email: email.to_owned(),
+            email_confirmed: false,
+            domain_events: vec![DomainEvent::UserRegisteredEvent {
+                user_id,
+                email: email.to_owned(),
+                user_type,
+            }],
+            user_type,
+            status: UserStatus::Active,
+        })
+    }
+
+    /// Checks every rule `change_email` enforces and reports all that are violated.
+    pub fn can_change_email(
+        &self,
+        new_email: &str,
+        company: &Company,
+    ) -> Result<(), UserManagementError> {
+        let mut preconditions = Preconditions::new();
+        preconditions.require(self.email_confirmed, || {
+            UserManagementError::EmailNotConfirmed(self.user_id)
+        });
+
+        match self.type_for_email(new_email, company) {
+            Ok(new_type) => {
+                preconditions
+                    .check(company.can_change_number_of_employees(self.employee_delta(new_type)));
+            }
+            Err(e) => {
+                preconditions.check::<()>(Err(e));
+            }
+        }
+
+        preconditions
+            .into_result()
+            .map_err(UserManagementError::from)
     }
 
-    pub fn change_email(&mut self, new_email: &str, company: &mut Company) {
+    pub fn change_email(
+        &mut self,
+        new_email: &str,
+        company: &mut Company,
+    ) -> Result<(), UserManagementError> {
+        self.can_change_email(new_email, company)?;
+
         if self.email == new_email {
-            return;
+            return Ok(());
         }
 
-        let new_type = if company.is_email_corporate(new_email) {
-            UserType::Employee
-        } else {
-            UserType::Cusotmer
-        };
+        let new_type = self.type_for_email(new_email, company)?;
 
         if self.user_type != new_type {
-            let delta = if new_type == UserType::Employee {
-                1
-            } else {
-                -1
-            };
-            company.change_number_of_employees(delta);
+            company.change_number_of_employees(self.employee_delta(new_type))?;
             self.domain_events.push(DomainEvent::UserTypeChangeEvent {
                 user_id: self.user_id,
                 old_type: self.user_type,
@@ -44,111 +105,776 @@ impl User {
             user_id: self.user_id,
             new_email: self.email.clone(),
         });
+
+        Ok(())
+    }
+
+    fn type_for_email(
+        &self,
+        email: &str,
+        company: &Company,
+    ) -> Result<UserType, UserManagementError> {
+        Ok(self.user_type.for_email(company.is_email_corporate(email)?))
+    }
+
+    fn employee_delta(&self, new_type: UserType) -> i64 {
+        i64::from(new_type.counts_toward_employees())
+            - i64::from(self.user_type.counts_toward_employees())
+    }
+
+    /// Rebuilds a user by applying `events` to the state recorded in `snapshot`.
+    pub fn rehydrate(snapshot: UserSnapshot, events: &[DomainEvent]) -> User {
+        let mut user = User {
+            user_id: snapshot.user_id,
+            email: snapshot.email,
+            email_confirmed: snapshot.email_confirmed,
+            domain_events: vec![],
+            user_type: snapshot.user_type,
+            status: snapshot.status,
+        };
+        for event in events {
+            user.apply(event);
+        }
+
+        user
+    }
+
+    /// Applies the state change `event` records, without raising it again.
+    pub fn apply(&mut self, event: &DomainEvent) {
+        match event {
+            DomainEvent::EmailChangeEvent { new_email, .. } => self.email = new_email.clone(),
+            DomainEvent::UserTypeChangeEvent { new_type, .. } => self.user_type = *new_type,
+            DomainEvent::UserRegisteredEvent {
+                email, user_type, ..
+            } => {
+                self.email = email.clone();
+                self.email_confirmed = false;
+                self.user_type = *user_type;
+                self.status = UserStatus::Active;
+            }
+            DomainEvent::EmailConfirmedEvent { .. } => self.email_confirmed = true,
+            DomainEvent::UserDeactivatedEvent { .. } => self.status = UserStatus::Deactivated,
+            DomainEvent::UserReactivatedEvent { .. } => self.status = UserStatus::Active,
+            DomainEvent::UserDeletedEvent { .. } => self.status = UserStatus::Deleted,
+            DomainEvent::EmailConfirmationRequestedEvent { .. }
+            | DomainEvent::EmployeeCountCorrectedEvent { .. } => {}
+        }
+    }
+
+    pub fn request_email_confirmation(&mut self, token: &ConfirmationToken) {
+        self.domain_events
+            .push(DomainEvent::EmailConfirmationRequestedEvent {
+                user_id: self.user_id,
+                email: self.email.clone(),
+                token: token.token.clone(),
+            });
+    }
+
+    pub fn confirm_email(&mut self) {
+        if self.email_confirmed {
+            return;
+        }
+
+        self.email_confirmed = true;
+        self.domain_events.push(DomainEvent::EmailConfirmedEvent {
+            user_id: self.user_id,
+        });
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.status == UserStatus::Active
+    }
+
+    /// Suspends the user until they are reactivated. An employee stops counting
+    /// toward the company's employees meanwhile.
+    pub fn deactivate(&mut self, company: &mut Company) -> Result<(), UserManagementError> {
+        match self.status {
+            UserStatus::Active => {}
+            UserStatus::Deactivated => return Ok(()),
+            UserStatus::Deleted => return Err(UserManagementError::UserDeleted(self.user_id)),
+        }
+
+        company.change_number_of_employees(-self.employee_count())?;
+        self.status = UserStatus::Deactivated;
+        self.domain_events.push(DomainEvent::UserDeactivatedEvent {
+            user_id: self.user_id,
+        });
+
+        Ok(())
+    }
+
+    pub fn reactivate(&mut self, company: &mut Company) -> Result<(), UserManagementError> {
+        match self.status {
+            UserStatus::Deactivated => {}
+            UserStatus::Active => return Ok(()),
+            UserStatus::Deleted => return Err(UserManagementError::UserDeleted(self.user_id)),
+        }
+
+        company.change_number_of_employees(self.employee_count())?;
+        self.status = UserStatus::Active;
+        self.domain_events.push(DomainEvent::UserReactivatedEvent {
+            user_id: self.user_id,
+        });
+
+        Ok(())
+    }
+
+    /// Removes the user for good. Their row is kept, marked as deleted, so their
+    /// email history stays intact.
+    pub fn delete(&mut self, company: &mut Company) -> Result<(), UserManagementError> {
+        match self.status {
+            UserStatus::Active => company.change_number_of_employees(-self.employee_count())?,
+            UserStatus::Deactivated => {}
+            UserStatus::Deleted => return Ok(()),
+        }
+
+        self.status = UserStatus::Deleted;
+        self.domain_events.push(DomainEvent::UserDeletedEvent {
+            user_id: self.user_id,
+        });
+
+        Ok(())
+    }
+
+    /// What this user adds to `Company::number_of_employees` while active.
+    fn employee_count(&self) -> i64 {
+        i64::from(self.user_type.counts_toward_employees())
     }
 }
 
-#[derive(PartialEq, Debug, Copy, Clone, derive_more::Display)]
+/// Whether a user can use the system. Only active users are returned by
+/// `Database::get_user_by_id`.
+#[derive(PartialEq, Debug, Copy, Clone, Default, derive_more::Display, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum UserStatus {
+    #[default]
+    #[display(fmt = "ACTIVE")]
+    Active,
+    #[display(fmt = "DEACTIVATED")]
+    Deactivated,
+    #[display(fmt = "DELETED")]
+    Deleted,
+}
+
+#[derive(PartialEq, Debug, Copy, Clone, derive_more::Display, Deserialize)]
+#[serde(try_from = "String")]
 pub enum UserType {
     #[display(fmt = "CUSTOMER")]
     Cusotmer,
     #[display(fmt = "EMPLOYEE")]
     Employee,
+    #[display(fmt = "CONTRACTOR")]
+    Contractor,
+    #[display(fmt = "ADMIN")]
+    Admin,
+}
+
+impl UserType {
+    /// Whether users of this type are included in `Company::number_of_employees`.
+    /// Contractors work for the company but are employed elsewhere.
+    pub fn counts_toward_employees(self) -> bool {
+        match self {
+            UserType::Employee | UserType::Admin => true,
+            UserType::Cusotmer | UserType::Contractor => false,
+        }
+    }
+
+    /// The type a user of this type has after moving to an address that is (or is
+    /// not) on the company domain. Contractors and admins are assigned by HR and
+    /// keep their type; everyone else is classified by the domain alone.
+    pub fn for_email(self, is_email_corporate: bool) -> UserType {
+        match self {
+            UserType::Contractor | UserType::Admin => self,
+            UserType::Cusotmer | UserType::Employee if is_email_corporate => UserType::Employee,
+            UserType::Cusotmer | UserType::Employee => UserType::Cusotmer,
+        }
+    }
 }
 
-impl From<String> for UserType {
-    fn from(v: String) -> Self {
-        match v.as_str() {
-            "CUSTOMER" => Self::Cusotmer,
-            _ => Self::Employee,
+#[derive(Debug, PartialEq, thiserror::Error)]
+#[error("unknown user type: {0:?}")]
+pub struct ParseUserTypeError(pub String);
+
+impl FromStr for UserType {
+    type Err = ParseUserTypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "CUSTOMER" => Ok(Self::Cusotmer),
+            "EMPLOYEE" => Ok(Self::Employee),
+            "CONTRACTOR" => Ok(Self::Contractor),
+            "ADMIN" => Ok(Self::Admin),
+            other => Err(ParseUserTypeError(other.to_owned())),
         }
     }
 }
 
+impl TryFrom<String> for UserType {
+    type Error = ParseUserTypeError;
+
+    fn try_from(v: String) -> Result<Self, Self::Error> {
+        v.parse()
+    }
+}
+
+impl Serialize for UserType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl FromSql for UserType {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Company {
     pub id: i64,
     pub domain_name: String,
     pub number_of_employees: i64,
+    pub version: i64,
 }
 
 impl Company {
-    fn change_number_of_employees(&mut self, delta: i64) {
-        assert!(
-            self.number_of_employees + delta >= 0,
-            "Number of employees must be greater than zero"
-        );
+    fn can_change_number_of_employees(&self, delta: i64) -> Result<(), UserManagementError> {
+        if self.number_of_employees + delta < 0 {
+            return Err(UserManagementError::EmployeeCountUnderflow {
+                company_id: self.id,
+                number_of_employees: self.number_of_employees,
+                delta,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn change_number_of_employees(&mut self, delta: i64) -> Result<(), UserManagementError> {
+        self.can_change_number_of_employees(delta)?;
 
         self.number_of_employees += delta;
+        Ok(())
     }
 
-    fn is_email_corporate(&self, email: &str) -> bool {
-        let email_domain = email
-            .split('@')
-            .nth(1)
-            .expect(&format!("email not contains '@', value: {}", email));
+    pub fn is_email_corporate(&self, email: &str) -> Result<bool, UserManagementError> {
+        let email_domain = email_domain(email)
+            .ok_or_else(|| UserManagementError::InvalidEmail(email.to_owned()))?;
 
-        email_domain == self.domain_name
+        Ok(email_domain == self.domain_name)
+    }
+}
+
+/// The domain of `email`, if it is a single non-empty local part and domain
+/// separated by exactly one `@`.
+pub fn email_domain(email: &str) -> Option<&str> {
+    match email.split_once('@') {
+        Some((local, domain))
+            if !local.is_empty() && !domain.is_empty() && !domain.contains('@') =>
+        {
+            Some(domain)
+        }
+        _ => None,
     }
 }
 
 pub trait Database {
     type Error: std::error::Error + Send + Sync + 'static;
-    fn get_user_by_id(&self, user_id: i64) -> Result<Option<User>, Self::Error>;
+    /// The user, unless they have been deactivated or deleted.
+    fn get_user_by_id(&self, user_id: i64) -> Result<Option<User>, Self::Error> {
+        Ok(self
+            .get_user_including_inactive(user_id)?
+            .filter(User::is_active))
+    }
+    fn get_user_including_inactive(&self, user_id: i64) -> Result<Option<User>, Self::Error>;
     fn get_company(&self) -> Result<Option<Company>, Self::Error>;
-    fn save_company(&self, company: &Company);
-    fn save_user(&self, user: &User) -> Result<(), Self::Error>;
+    fn get_companies(&self) -> Result<Vec<Company>, Self::Error>;
+    fn insert_company(&self, domain_name: &str) -> Result<i64, Self::Error>;
+    /// Active users who count toward the employees of the company on `domain_name`:
+    /// those with an address on its domain and, for the company the use cases
+    /// update (the first one), those on no company's domain, such as admins who
+    /// moved to a personal address.
+    fn get_employees(&self, domain_name: &str) -> Result<Vec<User>, Self::Error>;
+    fn count_employees(&self, domain_name: &str) -> Result<i64, Self::Error> {
+        Ok(self.get_employees(domain_name)?.len() as i64)
+    }
+    /// Persists `company`, failing with a conflict when the stored row has been
+    /// updated since `company` was loaded.
+    fn save_company(&self, company: &Company) -> Result<(), Self::Error>;
+    /// Adds `delta` to the stored employee count in place, failing with an
+    /// underflow if the count would go negative. Unlike `save_company` it never
+    /// overwrites a concurrent change to the count.
+    fn adjust_employee_count(&self, company_id: i64, delta: i64) -> Result<(), Self::Error>;
+    fn save_user(&self, user: &User) -> Result<(), Self::Error> {
+        self.save_user_as(user, SYSTEM_ACTOR)
+    }
+    /// Creates a user whose `email` is not confirmed yet and returns their id.
+    fn insert_user(&self, email: &str, user_type: UserType) -> Result<i64, Self::Error>;
+    /// Persists `user`, recording a change of their email as made by `actor`.
+    fn save_user_as(&self, user: &User, actor: &str) -> Result<(), Self::Error>;
+
+    /// The user's email changes, oldest first.
+    fn get_email_history(&self, user_id: i64) -> Result<Vec<EmailChange>, Self::Error>;
+    /// The user whose address `email` was at `at`, if any. Users created after `at`
+    /// or deleted by then own no address.
+    fn find_email_owner(&self, email: &str, at: DateTime<Utc>) -> Result<Option<i64>, Self::Error>;
+
+    /// Stores `events` in the outbox. Called inside the same unit of work as the
+    /// writes that raised them, so they are persisted if and only if those are.
+    fn add_to_outbox(&self, events: &[DomainEvent]) -> Result<(), Self::Error>;
+    fn get_undelivered_messages(&self, limit: usize) -> Result<Vec<OutboxMessage>, Self::Error>;
+    fn mark_delivered(&self, message_id: i64) -> Result<(), Self::Error>;
+
+    /// Keeps a message some subscribers kept rejecting, and which ones, so it can
+    /// be replayed to them later.
+    fn add_dead_letter(
+        &self,
+        envelope: &MessageEnvelope,
+        error: &DispatchError,
+    ) -> Result<(), Self::Error>;
+    /// Dead letters not yet replayed, oldest first.
+    fn get_dead_letters(&self) -> Result<Vec<DeadLetter>, Self::Error>;
+    fn mark_replayed(&self, dead_letter_id: i64) -> Result<(), Self::Error>;
+
+    fn save_confirmation_token(&self, token: &ConfirmationToken) -> Result<(), Self::Error>;
+    fn get_confirmation_token(&self, token: &str)
+        -> Result<Option<ConfirmationToken>, Self::Error>;
+
+    fn begin(&self) -> Result<(), Self::Error>;
+    fn commit(&self) -> Result<(), Self::Error>;
+    fn rollback(&self) -> Result<(), Self::Error>;
+
+    /// Runs `work` as a single unit of work: everything it writes is committed
+    /// together, or rolled back if it returns an error.
+    fn in_transaction<T, E>(&self, work: impl FnOnce(&Self) -> Result<T, E>) -> Result<T, E>
+    where
+        Self: Sized,
+        E: From<Self::Error>,
+    {
+        self.begin()?;
+
+        match work(self) {
+            Ok(value) => {
+                self.commit()?;
+                Ok(value)
+            }
+            Err(e) => {
+                // The original error is more useful to the caller than a failed rollback.
+                let _ = self.rollback();
+                Err(e)
+            }
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DatabaseError {
+    #[error(
+        "company {company_id} was modified concurrently (expected version {expected_version})"
+    )]
+    ConcurrencyConflict {
+        company_id: i64,
+        expected_version: i64,
+    },
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Serialization(#[from] serde_json::Error),
+    #[error("company {company_id} cannot go from {number_of_employees} employees by {delta}")]
+    EmployeeCountUnderflow {
+        company_id: i64,
+        number_of_employees: i64,
+        delta: i64,
+    },
+    #[error("no pooled connection became free within {0:?}")]
+    PoolTimeout(std::time::Duration),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum UserManagementError {
+    #[error("user {0} not found")]
+    UserNotFound(i64),
+    #[error("company not found")]
+    CompanyNotFound,
+    #[error("email of user {0} is not confirmed")]
+    EmailNotConfirmed(i64),
+    #[error("email of user {0} is already confirmed")]
+    EmailAlreadyConfirmed(i64),
+    #[error("invalid email: {0}")]
+    InvalidEmail(String),
+    #[error("user {0} has been deleted")]
+    UserDeleted(i64),
+    #[error("email {0} is already in use")]
+    EmailAlreadyInUse(String),
+    #[error("confirmation token not found")]
+    ConfirmationTokenNotFound,
+    #[error(transparent)]
+    InvalidConfirmationToken(#[from] TokenError),
+    #[error("company {company_id} cannot go from {number_of_employees} employees by {delta}")]
+    EmployeeCountUnderflow {
+        company_id: i64,
+        number_of_employees: i64,
+        delta: i64,
+    },
+    #[error("{}", .0.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("; "))]
+    PreconditionsFailed(Vec<UserManagementError>),
+    #[error("storage failure")]
+    Storage(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("messaging failure")]
+    Messaging(#[source] Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// A single violated rule is reported as itself; several as `PreconditionsFailed`.
+impl From<Vec<UserManagementError>> for UserManagementError {
+    fn from(mut violations: Vec<UserManagementError>) -> Self {
+        if violations.len() == 1 {
+            violations.remove(0)
+        } else {
+            Self::PreconditionsFailed(violations)
+        }
+    }
+}
+
+impl From<DatabaseError> for UserManagementError {
+    fn from(e: DatabaseError) -> Self {
+        match e {
+            DatabaseError::EmployeeCountUnderflow {
+                company_id,
+                number_of_employees,
+                delta,
+            } => Self::EmployeeCountUnderflow {
+                company_id,
+                number_of_employees,
+                delta,
+            },
+            e => Self::Storage(Box::new(e)),
+        }
+    }
+}
+
+impl From<BusError> for UserManagementError {
+    fn from(e: BusError) -> Self {
+        Self::Messaging(Box::new(e))
+    }
 }
 
 #[mockall::automock]
 pub trait Bus {
-    fn send(&self, message: &str);
+    fn send(&self, message: &str) -> Result<(), BusError>;
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct BusError(#[source] Box<dyn std::error::Error + Send + Sync>);
+
+impl BusError {
+    pub fn new(cause: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Self(cause.into())
+    }
+}
+
+impl From<std::io::Error> for BusError {
+    fn from(e: std::io::Error) -> Self {
+        Self::new(e)
+    }
+}
+
+impl From<&str> for BusError {
+    fn from(message: &str) -> Self {
+        Self::new(message)
+    }
 }
 
-#[derive(derive_more::Constructor)]
 pub struct MessageBus<B: Bus> {
     bus: B,
+    format: MessageFormat,
+    retry_policy: RetryPolicy,
 }
 
 impl<B: Bus> MessageBus<B> {
-    fn send_email_changed_message(&self, user_id: i64, new_email: &str) {
-        self.bus.send(&format!(
-            "Type: USER EMAIL CHANGED; Id: {}; NewEmail: {}",
-            user_id, new_email,
-        ));
+    /// A bus sending the legacy text format existing subscribers understand.
+    pub fn new(bus: B) -> Self {
+        Self::with_format(bus, MessageFormat::Legacy)
+    }
+
+    pub fn with_format(bus: B, format: MessageFormat) -> Self {
+        Self {
+            bus,
+            format,
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+
+    pub fn with_retry_policy(self, retry_policy: RetryPolicy) -> Self {
+        Self {
+            retry_policy,
+            ..self
+        }
+    }
+
+    /// Sends `envelope`, retrying as the policy allows before giving up.
+    pub fn publish(&self, envelope: &MessageEnvelope) -> Result<(), BusError> {
+        match self.format.encode(envelope) {
+            Some(message) => self.retry_policy.run(|| self.bus.send(&message)),
+            None => Ok(()),
+        }
     }
 }
 
 #[derive(derive_more::Constructor)]
-pub struct UserController<D: Database, L: DomainLogger, B: Bus> {
+pub struct UserController<D: Database> {
     pub database: D,
-    event_dispatcher: EventDispatcher<B, L>,
+    event_dispatcher: EventDispatcher,
 }
 
-impl<D: Database, L: DomainLogger, B: Bus> UserController<D, L, B> {
-    pub fn change_email(&self, user_id: i64, new_email: &str) -> anyhow::Result<()> {
-        let mut user = match self.database.get_user_by_id(user_id) {
-            Ok(result) => match result {
-                Some(user) => user,
-                None => return Err(anyhow::anyhow!("err")),
-            },
-            Err(e) => return Err(e.into()),
-        };
+impl<D: Database> UserController<D>
+where
+    UserManagementError: From<D::Error>,
+{
+    pub fn change_email(&self, user_id: i64, new_email: &str) -> Result<(), UserManagementError> {
+        self.change_email_as(user_id, new_email, SYSTEM_ACTOR)
+    }
 
-        if !user.can_change_email() {
-            return Err(anyhow::anyhow!("Cannot change email"));
-        }
+    /// Creates a user with `email`, an employee if it is on the company domain and
+    /// a customer otherwise, sends them a confirmation token and returns their id.
+    pub fn register_user(&self, email: &str) -> Result<i64, UserManagementError> {
+        let user = self
+            .database
+            .in_transaction(|database| register_user_in(database, email, Utc::now()))?;
+
+        self.relay_pending();
+
+        Ok(user.user_id)
+    }
+
+    /// Changes the user's email, recording `actor` as who made the change.
+    pub fn change_email_as(
+        &self,
+        user_id: i64,
+        new_email: &str,
+        actor: &str,
+    ) -> Result<(), UserManagementError> {
+        self.database
+            .in_transaction(|database| change_email_in(database, user_id, new_email, actor))?;
+
+        self.relay_pending();
+
+        Ok(())
+    }
+
+    /// Issues a new confirmation token for the user and sends it to their address.
+    pub fn request_email_confirmation(
+        &self,
+        user_id: i64,
+        now: DateTime<Utc>,
+    ) -> Result<(), UserManagementError> {
+        self.database
+            .in_transaction(|database| -> Result<(), UserManagementError> {
+                let mut user = database
+                    .get_user_by_id(user_id)?
+                    .ok_or(UserManagementError::UserNotFound(user_id))?;
+
+                if user.email_confirmed {
+                    return Err(UserManagementError::EmailAlreadyConfirmed(user_id));
+                }
+
+                let token = ConfirmationToken::issue(user.user_id, now);
+                user.request_email_confirmation(&token);
+
+                database.save_confirmation_token(&token)?;
+                database.add_to_outbox(&user.domain_events)?;
+
+                Ok(())
+            })?;
 
-        let mut company = self.database.get_company()?.unwrap();
+        self.relay_pending();
 
-        user.change_email(new_email, &mut company);
+        Ok(())
+    }
+
+    /// Confirms the email of the user `token` was issued to. Each token works once.
+    pub fn confirm_email(
+        &self,
+        token: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), UserManagementError> {
+        self.database
+            .in_transaction(|database| confirm_email_in(database, token, now))?;
+
+        self.relay_pending();
+
+        Ok(())
+    }
+
+    pub fn deactivate_user(&self, user_id: i64) -> Result<(), UserManagementError> {
+        self.change_status(user_id, User::deactivate)
+    }
 
-        self.database.save_company(&company);
-        self.database.save_user(&user)?;
+    pub fn reactivate_user(&self, user_id: i64) -> Result<(), UserManagementError> {
+        self.change_status(user_id, User::reactivate)
+    }
+
+    pub fn delete_user(&self, user_id: i64) -> Result<(), UserManagementError> {
+        self.change_status(user_id, User::delete)
+    }
+
+    fn change_status(
+        &self,
+        user_id: i64,
+        change: fn(&mut User, &mut Company) -> Result<(), UserManagementError>,
+    ) -> Result<(), UserManagementError> {
+        self.database
+            .in_transaction(|database| change_status_in(database, user_id, change))?;
 
-        self.event_dispatcher
-            .dispatch(user.domain_events.as_slice());
+        self.relay_pending();
 
         Ok(())
     }
+
+    /// Sends what the use case just committed to the outbox. The change itself has
+    /// already succeeded, so a failure here is only logged: the events stay in the
+    /// outbox for the next relay.
+    fn relay_pending(&self) {
+        if let Err(e) = OutboxRelay::new(&self.database, &self.event_dispatcher).relay_pending() {
+            tracing::warn!(error = %e, "failed to relay the outbox");
+        }
+    }
+}
+
+/// The unit of work behind `register_user`: classifies the email by the company
+/// domain and stores the unconfirmed user, their confirmation token, their effect
+/// on the employee count and the raised events. Run it in a transaction; nothing
+/// is written if the email is invalid or taken.
+pub(crate) fn register_user_in<D: Database>(
+    database: &D,
+    email: &str,
+    now: DateTime<Utc>,
+) -> Result<User, UserManagementError>
+where
+    UserManagementError: From<D::Error>,
+{
+    let mut company = database
+        .get_company()?
+        .ok_or(UserManagementError::CompanyNotFound)?;
+
+    let is_corporate = company.is_email_corporate(email)?;
+    if database.find_email_owner(email, now)?.is_some() {
+        return Err(UserManagementError::EmailAlreadyInUse(email.to_owned()));
+    }
+
+    let user_type = UserType::Cusotmer.for_email(is_corporate);
+    let user_id = database.insert_user(email, user_type)?;
+    let employees_before = company.number_of_employees;
+    let mut user = User::register(user_id, email, user_type, &mut company)?;
+    let token = ConfirmationToken::issue(user_id, now);
+    user.request_email_confirmation(&token);
+
+    let delta = company.number_of_employees - employees_before;
+    if delta != 0 {
+        database.adjust_employee_count(company.id, delta)?;
+    }
+    // Saving records the registration in the user's event stream, if there is one.
+    database.save_user(&user)?;
+    database.save_confirmation_token(&token)?;
+    database.add_to_outbox(&user.domain_events)?;
+
+    Ok(user)
+}
+
+/// The unit of work behind `change_email`: loads the user and company, changes
+/// the email and saves both along with the raised events. Run it in a transaction.
+pub(crate) fn change_email_in<D: Database>(
+    database: &D,
+    user_id: i64,
+    new_email: &str,
+    actor: &str,
+) -> Result<(), UserManagementError>
+where
+    UserManagementError: From<D::Error>,
+{
+    let mut user = database
+        .get_user_by_id(user_id)?
+        .ok_or(UserManagementError::UserNotFound(user_id))?;
+
+    let mut company = database
+        .get_company()?
+        .ok_or(UserManagementError::CompanyNotFound)?;
+
+    let employees_before = company.number_of_employees;
+    user.change_email(new_email, &mut company)?;
+
+    // Saved as a delta so a change committed by someone else since we read the
+    // company isn't overwritten.
+    let delta = company.number_of_employees - employees_before;
+    if delta != 0 {
+        database.adjust_employee_count(company.id, delta)?;
+    }
+    database.save_user_as(&user, actor)?;
+    database.add_to_outbox(&user.domain_events)?;
+
+    Ok(())
+}
+
+/// The unit of work behind `confirm_email`: redeems the token and confirms the
+/// email of the user it was issued to. Run it in a transaction.
+pub(crate) fn confirm_email_in<D: Database>(
+    database: &D,
+    token: &str,
+    now: DateTime<Utc>,
+) -> Result<(), UserManagementError>
+where
+    UserManagementError: From<D::Error>,
+{
+    let mut token = database
+        .get_confirmation_token(token)?
+        .ok_or(UserManagementError::ConfirmationTokenNotFound)?;
+    token.redeem(now)?;
+
+    let mut user = database
+        .get_user_by_id(token.user_id)?
+        .ok_or(UserManagementError::UserNotFound(token.user_id))?;
+    user.confirm_email();
+
+    database.save_confirmation_token(&token)?;
+    database.save_user(&user)?;
+    database.add_to_outbox(&user.domain_events)?;
+
+    Ok(())
+}
+
+/// The unit of work behind deactivating, reactivating and deleting: applies
+/// `change` to the user, whatever their status, and saves them, the employee
+/// count and the raised events. Run it in a transaction.
+pub(crate) fn change_status_in<D: Database>(
+    database: &D,
+    user_id: i64,
+    change: fn(&mut User, &mut Company) -> Result<(), UserManagementError>,
+) -> Result<(), UserManagementError>
+where
+    UserManagementError: From<D::Error>,
+{
+    let mut user = database
+        .get_user_including_inactive(user_id)?
+        .ok_or(UserManagementError::UserNotFound(user_id))?;
+
+    let mut company = database
+        .get_company()?
+        .ok_or(UserManagementError::CompanyNotFound)?;
+
+    let employees_before = company.number_of_employees;
+    change(&mut user, &mut company)?;
+
+    let delta = company.number_of_employees - employees_before;
+    if delta != 0 {
+        database.adjust_employee_count(company.id, delta)?;
+    }
+    database.save_user(&user)?;
+    database.add_to_outbox(&user.domain_events)?;
+
+    Ok(())
 }
 
 #[mockall::automock]
@@ -156,46 +882,223 @@ pub trait DomainLogger {
     fn user_type_has_changed(&self, user_id: i64, old_type: UserType, new_type: UserType);
 }
 
-#[derive(derive_more::Constructor)]
-pub struct EventDispatcher<B: Bus, L: DomainLogger> {
-    pub message_bus: MessageBus<B>,
-    pub domain_logger: L,
+/// Hands domain events to the subscribers registered for them.
+pub struct EventDispatcher {
+    subscribers: SubscriberRegistry,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(tag = "event_type", content = "payload")]
 pub enum DomainEvent {
-    EmailChangeEvent {
-        user_id: i64,
-        new_email: String,
-    },
+    #[serde(rename = "USER_EMAIL_CHANGED")]
+    EmailChangeEvent { user_id: i64, new_email: String },
+    #[serde(rename = "USER_TYPE_CHANGED")]
     UserTypeChangeEvent {
         user_id: i64,
         old_type: UserType,
         new_type: UserType,
     },
+    #[serde(rename = "EMAIL_CONFIRMATION_REQUESTED")]
+    EmailConfirmationRequestedEvent {
+        user_id: i64,
+        email: String,
+        token: String,
+    },
+    #[serde(rename = "USER_EMAIL_CONFIRMED")]
+    EmailConfirmedEvent { user_id: i64 },
+    #[serde(rename = "USER_REGISTERED")]
+    UserRegisteredEvent {
+        user_id: i64,
+        email: String,
+        user_type: UserType,
+    },
+    #[serde(rename = "USER_DEACTIVATED")]
+    UserDeactivatedEvent { user_id: i64 },
+    #[serde(rename = "USER_REACTIVATED")]
+    UserReactivatedEvent { user_id: i64 },
+    #[serde(rename = "USER_DELETED")]
+    UserDeletedEvent { user_id: i64 },
+    #[serde(rename = "EMPLOYEE_COUNT_CORRECTED")]
+    EmployeeCountCorrectedEvent {
+        company_id: i64,
+        old_count: i64,
+        new_count: i64,
+    },
+}
+
+#[derive(Debug)]
+pub struct OutboxMessage {
+    pub id: i64,
+    pub envelope: MessageEnvelope,
 }
 
-impl<B: Bus, L: DomainLogger> EventDispatcher<B, L> {
-    pub fn dispatch(&self, events: &[DomainEvent]) {
-        for e in events.into_iter() {
-            self._dispatch(e);
+impl EventDispatcher {
+    /// Logs user type changes to `domain_logger` and publishes every event to
+    /// `message_bus`, in that order, ahead of any subscribers added later.
+    pub fn new<B, L>(message_bus: MessageBus<B>, domain_logger: L) -> Self
+    where
+        B: Bus + Send + Sync + 'static,
+        L: DomainLogger + Send + Sync + 'static,
+    {
+        let mut subscribers = SubscriberRegistry::new();
+        subscribers.subscribe(
+            "domain_logger",
+            EventKind::UserTypeChanged,
+            DomainLoggerHandler(domain_logger),
+        );
+        subscribers.subscribe_all("message_bus", message_bus);
+
+        Self::with_subscribers(subscribers)
+    }
+
+    pub fn with_subscribers(subscribers: SubscriberRegistry) -> Self {
+        Self { subscribers }
+    }
+
+    pub fn subscribers_mut(&mut self) -> &mut SubscriberRegistry {
+        &mut self.subscribers
+    }
+
+    pub fn dispatch(&self, events: &[DomainEvent]) -> Result<(), DispatchError> {
+        for e in events.iter() {
+            self._dispatch(e)?;
         }
+
+        Ok(())
     }
 
-    pub fn _dispatch(&self, event: &DomainEvent) {
-        match event {
-            DomainEvent::EmailChangeEvent { user_id, new_email } => {
-                self.message_bus
-                    .send_email_changed_message(*user_id, new_email);
-            }
-            DomainEvent::UserTypeChangeEvent {
-                user_id,
-                old_type,
-                new_type,
-            } => {
-                self.domain_logger
-                    .user_type_has_changed(*user_id, *old_type, *new_type);
-            }
+    pub fn _dispatch(&self, event: &DomainEvent) -> Result<(), DispatchError> {
+        self.dispatch_envelope(&MessageEnvelope::new(
+            event.clone(),
+            Uuid::new_v4(),
+            Utc::now(),
+        ))
+    }
+
+    /// Dispatches an event whose id and timestamp were assigned when it was raised,
+    /// so redelivered copies can be recognised by consumers.
+    pub fn dispatch_envelope(&self, envelope: &MessageEnvelope) -> Result<(), DispatchError> {
+        self.subscribers.publish(envelope)
+    }
+
+    /// Dispatches `envelope` again to the named subscribers only.
+    pub fn redispatch_envelope(
+        &self,
+        envelope: &MessageEnvelope,
+        subscribers: &[String],
+    ) -> Result<(), DispatchError> {
+        self.subscribers.publish_to(envelope, subscribers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn company(number_of_employees: i64) -> Company {
+        Company {
+            id: 1,
+            domain_name: "mycorp.com".to_owned(),
+            number_of_employees,
+            version: 0,
+        }
+    }
+
+    fn user(email: &str, user_type: UserType) -> User {
+        User {
+            user_id: 1,
+            email: email.to_owned(),
+            email_confirmed: true,
+            domain_events: vec![],
+            user_type,
+            status: UserStatus::Active,
+        }
+    }
+
+    #[test]
+    fn user_types_round_trip_through_their_names() {
+        for user_type in [
+            UserType::Cusotmer,
+            UserType::Employee,
+            UserType::Contractor,
+            UserType::Admin,
+        ] {
+            assert_eq!(Ok(user_type), user_type.to_string().parse());
         }
     }
+
+    #[test]
+    fn unknown_user_type_is_rejected() {
+        assert_eq!(
+            Err(ParseUserTypeError("EMPLOYE".to_owned())),
+            "EMPLOYE".parse::<UserType>()
+        );
+        assert!(UserType::try_from("employee".to_owned()).is_err());
+    }
+
+    #[test]
+    fn contractor_moving_to_a_corporate_address_stays_a_contractor() {
+        let mut company = company(3);
+        let mut sut = user("contractor@agency.com", UserType::Contractor);
+
+        sut.change_email("contractor@mycorp.com", &mut company)
+            .unwrap();
+
+        assert_eq!(UserType::Contractor, sut.user_type);
+        assert_eq!(3, company.number_of_employees);
+    }
+
+    #[test]
+    fn admin_leaving_the_corporate_domain_is_still_counted() {
+        let mut company = company(3);
+        let mut sut = user("admin@mycorp.com", UserType::Admin);
+
+        sut.change_email("admin@example.com", &mut company).unwrap();
+
+        assert_eq!(UserType::Admin, sut.user_type);
+        assert_eq!(3, company.number_of_employees);
+        assert_eq!(
+            vec![DomainEvent::EmailChangeEvent {
+                user_id: 1,
+                new_email: "admin@example.com".to_owned()
+            }],
+            sut.domain_events
+        );
+    }
+
+    #[test]
+    fn deactivated_employee_is_not_counted_until_reactivated() {
+        let mut company = company(3);
+        let mut sut = user("user@mycorp.com", UserType::Employee);
+
+        sut.deactivate(&mut company).unwrap();
+        sut.deactivate(&mut company).unwrap();
+        let employees_while_deactivated = company.number_of_employees;
+        sut.reactivate(&mut company).unwrap();
+
+        assert_eq!(2, employees_while_deactivated);
+        assert_eq!(3, company.number_of_employees);
+        assert!(sut.is_active());
+        assert_eq!(
+            vec![
+                DomainEvent::UserDeactivatedEvent { user_id: 1 },
+                DomainEvent::UserReactivatedEvent { user_id: 1 }
+            ],
+            sut.domain_events
+        );
+    }
+
+    #[test]
+    fn deleted_user_cannot_be_reactivated() {
+        let mut company = company(3);
+        let mut sut = user("user@mycorp.com", UserType::Employee);
+        sut.deactivate(&mut company).unwrap();
+        sut.delete(&mut company).unwrap();
+
+        let result = sut.reactivate(&mut company);
+
+        assert!(matches!(result, Err(UserManagementError::UserDeleted(1))));
+        assert_eq!(UserStatus::Deleted, sut.status);
+        assert_eq!(2, company.number_of_employees);
+    }
 }
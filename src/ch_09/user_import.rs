@@ -0,0 +1,221 @@
+use chrono::Utc;
+
+use super::outbox::OutboxRelay;
+use super::types::{register_user_in, Database, EventDispatcher, UserManagementError, UserType};
+
+/// The outcome of importing one CSV row.
+#[derive(Debug)]
+pub struct ImportedRow {
+    /// 1-based line number in the CSV.
+    pub line: usize,
+    pub email: String,
+    /// The id and type of the created user, or why the row was skipped.
+    pub result: Result<(i64, UserType), UserManagementError>,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub rows: Vec<ImportedRow>,
+}
+
+impl ImportReport {
+    pub fn imported(&self) -> usize {
+        self.rows.iter().filter(|row| row.result.is_ok()).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.rows.len() - self.imported()
+    }
+}
+
+/// Registers a user for every email in `csv`, exactly as `UserController::register_user`
+/// would, but with every row in one transaction. The raised events are then sent
+/// like the controller sends them: a failure is only logged, and they stay in the
+/// outbox for the next relay.
+///
+/// The email is the first column of each row, which may be quoted; blank lines and
+/// an `email` header are skipped. Rows with an invalid or already used address are
+/// reported and skipped.
+pub fn import_users<D: Database>(
+    database: &D,
+    event_dispatcher: &EventDispatcher,
+    csv: &str,
+) -> Result<ImportReport, UserManagementError>
+where
+    UserManagementError: From<D::Error>,
+{
+    let report =
+        database.in_transaction(|database| -> Result<ImportReport, UserManagementError> {
+            let mut report = ImportReport::default();
+            let mut reader = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .flexible(true)
+                .trim(csv::Trim::All)
+                .from_reader(csv.as_bytes());
+
+            for (index, record) in reader.records().enumerate() {
+                // Rows of any length are accepted, and a str is always valid UTF-8.
+                let record = record.expect("a flexible CSV read from a str always parses");
+                let email = record.get(0).unwrap_or_default();
+                if email.is_empty() || (index == 0 && email.eq_ignore_ascii_case("email")) {
+                    continue;
+                }
+
+                let result = match register_user_in(database, email, Utc::now()) {
+                    Ok(user) => Ok((user.user_id, user.user_type)),
+                    // Only a rejected row is skipped; anything else aborts the import.
+                    Err(
+                        e @ (UserManagementError::InvalidEmail(_)
+                        | UserManagementError::EmailAlreadyInUse(_)),
+                    ) => Err(e),
+                    Err(e) => return Err(e),
+                };
+
+                report.rows.push(ImportedRow {
+                    line: record.position().map_or(index + 1, |p| line_of(csv, p)),
+                    email: email.to_owned(),
+                    result,
+                });
+            }
+
+            Ok(report)
+        })?;
+
+    if let Err(e) = OutboxRelay::new(database, event_dispatcher).relay_pending() {
+        tracing::warn!(error = %e, "failed to relay the outbox");
+    }
+
+    Ok(report)
+}
+
+/// The 1-based line a record starts on. The reader places a record after blank
+/// lines at the first of them.
+fn line_of(csv: &str, position: &csv::Position) -> usize {
+    let blank_lines = csv[position.byte() as usize..]
+        .chars()
+        .take_while(|c| matches!(c, '\r' | '\n'))
+        .filter(|&c| c == '\n')
+        .count();
+
+    position.line() as usize + blank_lines
+}
+
+#[cfg(test)]
+mod test {
+    use std::error;
+
+    use crate::ch_09::bus_message::MessageFormat;
+    use crate::ch_09::sqlite_database::SQLiteDatabase;
+    use crate::ch_09::test_helper::test_helper::{create_company, create_db, create_user};
+    use crate::ch_09::types::{MessageBus, MockBus, MockDomainLogger};
+
+    use super::*;
+
+    fn dispatcher(bus: MockBus) -> EventDispatcher {
+        EventDispatcher::new(
+            MessageBus::with_format(bus, MessageFormat::Json),
+            MockDomainLogger::new(),
+        )
+    }
+
+    #[test]
+    fn imports_users_and_counts_new_employees() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let mut db = SQLiteDatabase::new(create_db()?);
+        create_user(&mut db.conn, "taken@example.com", UserType::Cusotmer)?;
+        create_company(&mut db.conn, "mycorp.com", 3)?;
+        let csv = "email,name\n\
+                   alice@mycorp.com,Alice\n\
+                   bob@example.com\n\
+                   \n\
+                   not-an-email\n\
+                   taken@example.com\n\
+                   carol@mycorp.com\n\
+                   carol@mycorp.com\n";
+
+        let mut bus_mock = MockBus::new();
+        bus_mock
+            .expect_send()
+            .withf(|message| message.contains("\"event_type\":\"USER_REGISTERED\""))
+            .times(3)
+            .returning(|_| Ok(()));
+        bus_mock
+            .expect_send()
+            .withf(|message| message.contains("\"event_type\":\"EMAIL_CONFIRMATION_REQUESTED\""))
+            .times(3)
+            .returning(|_| Ok(()));
+
+        // Act
+        let report = import_users(&db, &dispatcher(bus_mock), csv)?;
+
+        // Assert
+        assert_eq!(3, report.imported());
+        assert_eq!(3, report.failed());
+        let lines: Vec<_> = report.rows.iter().map(|row| row.line).collect();
+        assert_eq!(vec![2, 3, 5, 6, 7, 8], lines);
+        assert!(matches!(report.rows[0].result, Ok((2, UserType::Employee))));
+        assert!(matches!(report.rows[1].result, Ok((3, UserType::Cusotmer))));
+        assert!(matches!(
+            report.rows[2].result,
+            Err(UserManagementError::InvalidEmail(_))
+        ));
+        assert!(matches!(
+            &report.rows[3].result,
+            Err(UserManagementError::EmailAlreadyInUse(email)) if email == "taken@example.com"
+        ));
+        assert!(matches!(
+            report.rows[5].result,
+            Err(UserManagementError::EmailAlreadyInUse(_))
+        ));
+
+        let alice = db.get_user_by_id(2)?.unwrap();
+        assert_eq!("alice@mycorp.com", alice.email);
+        assert!(!alice.email_confirmed);
+        let company = db.get_company()?.unwrap();
+        assert_eq!(5, company.number_of_employees);
+        assert!(db.get_undelivered_messages(10)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn nothing_is_imported_without_a_company() -> Result<(), Box<dyn error::Error>> {
+        let db = SQLiteDatabase::new(create_db()?);
+
+        let result = import_users(&db, &dispatcher(MockBus::new()), "alice@mycorp.com\n");
+
+        assert!(matches!(result, Err(UserManagementError::CompanyNotFound)));
+        assert_eq!(None, db.find_email_owner("alice@mycorp.com", Utc::now())?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn quoted_fields_are_unquoted() -> Result<(), Box<dyn error::Error>> {
+        // Arrange
+        let mut db = SQLiteDatabase::new(create_db()?);
+        create_company(&mut db.conn, "mycorp.com", 0)?;
+        let csv = "email,name\n\
+                   \"alice@mycorp.com\",\"Smith, Alice\"\n\
+                   bob@example.com,\"Jones,\nBob\"\n\
+                   carol@mycorp.com\n";
+        let mut bus_mock = MockBus::new();
+        bus_mock.expect_send().returning(|_| Ok(()));
+
+        // Act
+        let report = import_users(&db, &dispatcher(bus_mock), csv)?;
+
+        // Assert
+        let emails: Vec<_> = report.rows.iter().map(|row| row.email.as_str()).collect();
+        assert_eq!(
+            vec!["alice@mycorp.com", "bob@example.com", "carol@mycorp.com"],
+            emails
+        );
+        assert_eq!(3, report.imported());
+        // Bob's name spans two lines.
+        let lines: Vec<_> = report.rows.iter().map(|row| row.line).collect();
+        assert_eq!(vec![2, 3, 5], lines);
+
+        Ok(())
+    }
+}
@@ -5,36 +5,38 @@ struct SQLiteDatabase {
 }
 
 impl Database for SQLiteDatabase {
-    type Error = rusqlite::Error;
+    type Error = DatabaseError;
     fn get_user_by_id(&self, user_id: i64) -> Result<Option<User>, Self::Error> {
         let mut stmt = self
             .conn
             .prepare("SELECT id, email, email_confirmed, user_type FROM user WHERE id = ?1")?;
         let user = stmt
             .query_map([user_id], |row| {
-                let user_type: String = row.get(3)?;
                 Ok(User {
                     user_id: row.get(0)?,
                     email: row.get(1)?,
                     email_confirmed: row.get(2)?,
                     domain_events: vec![],
-                    user_type: UserType::from(user_type),
+                    user_type: row.get(3)?,
                 })
             })?
             .next()
-            .transpose();
+            .transpose()?;
 
-        user
+        Ok(user)
     }
 
     fn get_company(&self) -> Result<Option<Company>, Self::Error> {
-        let mut stmt = self.conn.prepare("SELECT * from company limit 1")?;
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id, domain, number_of_employees, version FROM company LIMIT 1")?;
         let company = stmt
             .query_map([], |row| {
                 Ok(Company {
                     id: row.get(0)?,
                     domain_name: row.get(1)?,
                     number_of_employees: row.get(2)?,
+                    version: row.get(3)?,
                 })
             })?
             .next()
@@ -43,7 +45,27 @@ impl Database for SQLiteDatabase {
         Ok(company)
     }
 
-    fn save_company(&self, company: &Company) {}
+    fn save_company(&self, company: &Company) -> Result<(), Self::Error> {
+        let updated = self.conn.execute(
+            "UPDATE company SET domain = ?1, number_of_employees = ?2, version = version + 1
+             WHERE id = ?3 AND version = ?4",
+            (
+                &company.domain_name,
+                company.number_of_employees,
+                company.id,
+                company.version,
+            ),
+        )?;
+
+        if updated == 0 {
+            return Err(DatabaseError::ConcurrencyConflict {
+                company_id: company.id,
+                expected_version: company.version,
+            });
+        }
+
+        Ok(())
+    }
 
     fn save_user(&self, user: &User) -> Result<(), Self::Error> {
         self.conn.execute(
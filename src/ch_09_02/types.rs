@@ -1,3 +1,6 @@
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ValueRef};
+use std::str::FromStr;
+
 #[derive(Debug)]
 pub struct User {
     pub user_id: i64,
@@ -55,19 +58,44 @@ pub enum UserType {
     Employee,
 }
 
-impl From<String> for UserType {
-    fn from(v: String) -> Self {
-        match v.as_str() {
-            "CUSTOMER" => Self::Cusotmer,
-            _ => Self::Employee,
+#[derive(Debug, PartialEq, thiserror::Error)]
+#[error("unknown user type: {0:?}")]
+pub struct ParseUserTypeError(pub String);
+
+impl FromStr for UserType {
+    type Err = ParseUserTypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "CUSTOMER" => Ok(Self::Cusotmer),
+            "EMPLOYEE" => Ok(Self::Employee),
+            other => Err(ParseUserTypeError(other.to_owned())),
         }
     }
 }
 
+impl TryFrom<String> for UserType {
+    type Error = ParseUserTypeError;
+
+    fn try_from(v: String) -> Result<Self, Self::Error> {
+        v.parse()
+    }
+}
+
+impl FromSql for UserType {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
 pub struct Company {
     pub id: i64,
     pub domain_name: String,
     pub number_of_employees: i64,
+    pub version: i64,
 }
 
 impl Company {
@@ -94,10 +122,25 @@ pub trait Database {
     type Error: std::error::Error + Send + Sync + 'static;
     fn get_user_by_id(&self, user_id: i64) -> Result<Option<User>, Self::Error>;
     fn get_company(&self) -> Result<Option<Company>, Self::Error>;
-    fn save_company(&self, company: &Company);
+    /// Persists `company`, failing with a conflict when the stored row has been
+    /// updated since `company` was loaded.
+    fn save_company(&self, company: &Company) -> Result<(), Self::Error>;
     fn save_user(&self, user: &User) -> Result<(), Self::Error>;
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum DatabaseError {
+    #[error(
+        "company {company_id} was modified concurrently (expected version {expected_version})"
+    )]
+    ConcurrencyConflict {
+        company_id: i64,
+        expected_version: i64,
+    },
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+}
+
 #[mockall::automock]
 pub trait Bus {
     fn send(&self, message: &str);
@@ -141,7 +184,7 @@ impl<D: Database, L: DomainLogger, B: Bus> UserController<D, L, B> {
 
         user.change_email(new_email, &mut company);
 
-        self.database.save_company(&company);
+        self.database.save_company(&company)?;
         self.database.save_user(&user)?;
 
         self.event_dispatcher
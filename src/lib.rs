@@ -8,6 +8,7 @@ pub mod ch_08;
 pub mod ch_09;
 pub mod ch_09_02;
 pub mod ch_09_03;
+pub mod migrations;
 
 pub fn add(left: usize, right: usize) -> usize {
     left + right
@@ -0,0 +1,461 @@
+use rusqlite::{Connection, OptionalExtension};
+
+/// A schema change applied to the user/company database exactly once.
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+/// Every migration, in the order it must be applied. Append new ones to the end;
+/// never edit one that has already shipped.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create user and company tables",
+        sql: "
+            CREATE TABLE user (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                email TEXT NOT NULL,
+                email_confirmed INT NOT NULL DEFAULT TRUE,
+                user_type TEXT collate BINARY NOT NULL,
+                CHECK (user_type = 'CUSTOMER' OR user_type = 'EMPLOYEE')
+            );
+            CREATE TABLE company (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                domain TEXT NOT NULL,
+                number_of_employees INTEGER NOT NULL
+            );
+        ",
+    },
+    Migration {
+        version: 2,
+        description: "add company version for optimistic concurrency",
+        sql: "ALTER TABLE company ADD COLUMN version INTEGER NOT NULL DEFAULT 0;",
+    },
+    Migration {
+        version: 3,
+        description: "create outbox",
+        sql: "
+            CREATE TABLE outbox (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                event_type TEXT NOT NULL,
+                user_id INTEGER NOT NULL,
+                new_email TEXT,
+                old_type TEXT,
+                new_type TEXT,
+                created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                delivered_at TEXT
+            );
+        ",
+    },
+    Migration {
+        version: 4,
+        description: "create email confirmation tokens",
+        sql: "
+            CREATE TABLE email_confirmation_token (
+                token TEXT PRIMARY KEY,
+                user_id INTEGER NOT NULL REFERENCES user (id),
+                expires_at TEXT NOT NULL,
+                used_at TEXT
+            );
+            ALTER TABLE outbox ADD COLUMN token TEXT;
+        ",
+    },
+    Migration {
+        version: 5,
+        description: "allow contractor and admin user types",
+        // SQLite cannot alter a CHECK constraint, so the table is rebuilt.
+        sql: "
+            CREATE TABLE user_new (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                email TEXT NOT NULL,
+                email_confirmed INT NOT NULL DEFAULT TRUE,
+                user_type TEXT collate BINARY NOT NULL,
+                CHECK (user_type IN ('CUSTOMER', 'EMPLOYEE', 'CONTRACTOR', 'ADMIN'))
+            );
+            INSERT INTO user_new (id, email, email_confirmed, user_type)
+                SELECT id, email, email_confirmed, user_type FROM user;
+            DROP TABLE user;
+            ALTER TABLE user_new RENAME TO user;
+        ",
+    },
+    Migration {
+        version: 6,
+        description: "give outbox messages a stable event id and timestamp",
+        sql: "
+            ALTER TABLE outbox ADD COLUMN event_id TEXT;
+            ALTER TABLE outbox ADD COLUMN occurred_at TEXT;
+            UPDATE outbox SET event_id = lower(hex(randomblob(16))), occurred_at = created_at;
+        ",
+    },
+    Migration {
+        version: 7,
+        description: "create user event store",
+        sql: "
+            CREATE TABLE user_event (
+                user_id INTEGER NOT NULL,
+                sequence INTEGER NOT NULL,
+                event_id TEXT NOT NULL,
+                event TEXT NOT NULL,
+                occurred_at TEXT NOT NULL,
+                PRIMARY KEY (user_id, sequence)
+            );
+            CREATE TABLE user_snapshot (
+                user_id INTEGER NOT NULL,
+                sequence INTEGER NOT NULL,
+                state TEXT NOT NULL,
+                PRIMARY KEY (user_id, sequence)
+            );
+        ",
+    },
+    Migration {
+        version: 8,
+        description: "create email history table",
+        sql: "
+            CREATE TABLE email_history (
+                id INTEGER PRIMARY KEY,
+                user_id INTEGER NOT NULL REFERENCES user(id),
+                old_email TEXT NOT NULL,
+                new_email TEXT NOT NULL,
+                old_type TEXT NOT NULL,
+                new_type TEXT NOT NULL,
+                changed_at TEXT NOT NULL,
+                actor TEXT NOT NULL
+            );
+            CREATE INDEX email_history_user_id ON email_history (user_id);
+        ",
+    },
+    Migration {
+        version: 9,
+        description: "allow company events in the outbox",
+        // SQLite cannot drop NOT NULL from user_id, so the table is rebuilt.
+        sql: "
+            CREATE TABLE outbox_new (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                event_type TEXT NOT NULL,
+                user_id INTEGER,
+                new_email TEXT,
+                old_type TEXT,
+                new_type TEXT,
+                created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                delivered_at TEXT,
+                token TEXT,
+                event_id TEXT,
+                occurred_at TEXT,
+                company_id INTEGER,
+                old_count INTEGER,
+                new_count INTEGER
+            );
+            INSERT INTO outbox_new
+                (id, event_type, user_id, new_email, old_type, new_type, created_at,
+                 delivered_at, token, event_id, occurred_at)
+                SELECT id, event_type, user_id, new_email, old_type, new_type, created_at,
+                       delivered_at, token, event_id, occurred_at
+                FROM outbox;
+            DROP TABLE outbox;
+            ALTER TABLE outbox_new RENAME TO outbox;
+        ",
+    },
+    Migration {
+        version: 10,
+        description: "create dead letter store",
+        sql: "
+            CREATE TABLE dead_letter (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                envelope TEXT NOT NULL,
+                error TEXT NOT NULL,
+                failed_at TEXT NOT NULL,
+                replayed_at TEXT
+            );
+        ",
+    },
+    Migration {
+        version: 11,
+        description: "add user deactivation and soft delete",
+        sql: "
+            ALTER TABLE user ADD COLUMN deactivated_at TEXT;
+            ALTER TABLE user ADD COLUMN deleted_at TEXT;
+        ",
+    },
+    Migration {
+        version: 12,
+        description: "store outbox messages as serialized envelopes",
+        // Each event kind used its own subset of columns; rows are converted to the
+        // envelope JSON the relay sends, like dead letters already are.
+        sql: "
+            CREATE TABLE outbox_new (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                envelope TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                delivered_at TEXT
+            );
+            INSERT INTO outbox_new (id, envelope, created_at, delivered_at)
+                SELECT id,
+                       json_object(
+                           'schema_version', 1,
+                           'event_id', COALESCE(event_id, lower(hex(randomblob(16)))),
+                           'timestamp',
+                               strftime('%Y-%m-%dT%H:%M:%fZ', COALESCE(occurred_at, created_at)),
+                           'event_type', CASE event_type
+                               WHEN 'EMAIL_CHANGED' THEN 'USER_EMAIL_CHANGED'
+                               WHEN 'EMAIL_CONFIRMED' THEN 'USER_EMAIL_CONFIRMED'
+                               ELSE event_type
+                           END,
+                           'payload', CASE
+                               WHEN event_type = 'EMAIL_CHANGED'
+                                   THEN json_object('user_id', user_id, 'new_email', new_email)
+                               WHEN event_type = 'USER_TYPE_CHANGED'
+                                   THEN json_object('user_id', user_id, 'old_type', old_type,
+                                                    'new_type', new_type)
+                               WHEN event_type = 'EMAIL_CONFIRMATION_REQUESTED'
+                                   THEN json_object('user_id', user_id, 'email', new_email,
+                                                    'token', token)
+                               WHEN event_type = 'USER_REGISTERED'
+                                   THEN json_object('user_id', user_id, 'email', new_email,
+                                                    'user_type', new_type)
+                               WHEN event_type = 'EMPLOYEE_COUNT_CORRECTED'
+                                   THEN json_object('company_id', company_id,
+                                                    'old_count', old_count,
+                                                    'new_count', new_count)
+                               WHEN event_type IN ('EMAIL_CONFIRMED', 'USER_DEACTIVATED',
+                                                   'USER_REACTIVATED', 'USER_DELETED')
+                                   THEN json_object('user_id', user_id)
+                           END
+                       ),
+                       created_at,
+                       delivered_at
+                FROM outbox;
+            DROP TABLE outbox;
+            ALTER TABLE outbox_new RENAME TO outbox;
+        ",
+    },
+    Migration {
+        version: 13,
+        description: "record which subscribers a dead letter failed for",
+        sql: "ALTER TABLE dead_letter ADD COLUMN subscribers TEXT;",
+    },
+    Migration {
+        version: 14,
+        description: "record when each user was created",
+        // Existing users keep NULL: when they were created is unknown.
+        sql: "ALTER TABLE user ADD COLUMN created_at TEXT;",
+    },
+];
+
+/// Brings the database up to date with [`MIGRATIONS`] and returns how many were applied.
+pub fn migrate(conn: &mut Connection) -> rusqlite::Result<usize> {
+    migrate_to(conn, MIGRATIONS)
+}
+
+fn migrate_to(conn: &mut Connection, migrations: &[Migration]) -> rusqlite::Result<usize> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        )",
+        (),
+    )?;
+
+    let current = current_version(conn)?.unwrap_or(0);
+    let mut applied = 0;
+
+    // Rebuilding a table drops the old one, which would fail (or cascade) on rows
+    // referencing it while foreign keys are enforced. Enforcement can only be
+    // switched outside a transaction, so it is off for the whole run and the
+    // references are checked before each migration commits instead.
+    let foreign_keys: bool = conn.query_row("PRAGMA foreign_keys", [], |row| row.get(0))?;
+    conn.execute_batch("PRAGMA foreign_keys = OFF")?;
+
+    let result = (|| {
+        for migration in migrations.iter().filter(|m| m.version > current) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration.sql)?;
+            check_foreign_keys(&tx, migration)?;
+            tx.execute(
+                "INSERT INTO schema_migrations (version, description) VALUES (?1, ?2)",
+                (migration.version, migration.description),
+            )?;
+            tx.commit()?;
+
+            applied += 1;
+        }
+
+        Ok(applied)
+    })();
+
+    if foreign_keys {
+        conn.execute_batch("PRAGMA foreign_keys = ON")?;
+    }
+
+    result
+}
+
+/// Fails if `migration` left any row referencing one that does not exist.
+fn check_foreign_keys(conn: &Connection, migration: &Migration) -> rusqlite::Result<()> {
+    let violation: Option<String> = conn
+        .query_row("PRAGMA foreign_key_check", [], |row| row.get(0))
+        .optional()?;
+
+    match violation {
+        Some(table) => Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CONSTRAINT_FOREIGNKEY),
+            Some(format!(
+                "migration {} left rows in {} with dangling foreign keys",
+                migration.version, table
+            )),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// The version of the last applied migration, if any.
+pub fn current_version(conn: &Connection) -> rusqlite::Result<Option<i64>> {
+    conn.query_row("SELECT MAX(version) FROM schema_migrations", [], |row| {
+        row.get(0)
+    })
+    .optional()
+    .map(Option::flatten)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ch_09::bus_message::MessageEnvelope;
+    use crate::ch_09::types::{DomainEvent, UserType};
+
+    #[test]
+    fn migrating_a_new_database_applies_every_migration() -> rusqlite::Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+
+        let applied = migrate(&mut conn)?;
+
+        assert_eq!(MIGRATIONS.len(), applied);
+        assert_eq!(
+            MIGRATIONS.last().map(|m| m.version),
+            current_version(&conn)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn migrating_twice_applies_nothing_the_second_time() -> rusqlite::Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrate(&mut conn)?;
+
+        let applied = migrate(&mut conn)?;
+
+        assert_eq!(0, applied);
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_pending_migrations_are_applied() -> rusqlite::Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrate_to(&mut conn, &MIGRATIONS[..1])?;
+        conn.execute(
+            "INSERT INTO company (domain, number_of_employees) VALUES ('mycorp.com', 3)",
+            (),
+        )?;
+
+        let applied = migrate(&mut conn)?;
+
+        assert_eq!(MIGRATIONS.len() - 1, applied);
+        let (employees, version): (i64, i64) = conn.query_row(
+            "SELECT number_of_employees, version FROM company",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        assert_eq!((3, 0), (employees, version));
+
+        Ok(())
+    }
+
+    #[test]
+    fn rebuilding_the_user_table_keeps_rows_that_reference_it() -> rusqlite::Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrate_to(&mut conn, &MIGRATIONS[..4])?;
+        conn.execute_batch(
+            "INSERT INTO user (email, user_type) VALUES ('user@mycorp.com', 'EMPLOYEE');
+             INSERT INTO email_confirmation_token (token, user_id, expires_at)
+                 VALUES ('abc', 1, '2023-02-18T12:00:00Z');",
+        )?;
+
+        let applied = migrate(&mut conn)?;
+
+        assert_eq!(MIGRATIONS.len() - 4, applied);
+        let user_id: i64 = conn.query_row(
+            "SELECT user_id FROM email_confirmation_token WHERE token = 'abc'",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(1, user_id);
+        let foreign_keys: bool = conn.query_row("PRAGMA foreign_keys", [], |row| row.get(0))?;
+        assert!(foreign_keys);
+
+        Ok(())
+    }
+
+    #[test]
+    fn outbox_rows_are_converted_to_envelopes() -> Result<(), Box<dyn std::error::Error>> {
+        let mut conn = Connection::open_in_memory()?;
+        migrate_to(&mut conn, &MIGRATIONS[..11])?;
+        conn.execute_batch(
+            "INSERT INTO outbox (event_type, user_id, new_email, event_id, occurred_at)
+                 VALUES ('EMAIL_CHANGED', 1, 'new@example.com',
+                         'f0a2f6d2-3e43-4a36-8d0b-0c2e3c1f5b7a',
+                         '2023-02-18 12:00:00.5+00:00');
+             INSERT INTO outbox (event_type, user_id, old_type, new_type, event_id, occurred_at)
+                 VALUES ('USER_TYPE_CHANGED', 1, 'EMPLOYEE', 'CUSTOMER',
+                         '0d3c1e6f5a2b4c7d8e9f0a1b2c3d4e5f', '2023-02-18 12:00:01+00:00');
+             INSERT INTO outbox (event_type, company_id, old_count, new_count, event_id,
+                                 occurred_at)
+                 VALUES ('EMPLOYEE_COUNT_CORRECTED', 1, 3, 2,
+                         '5b2d8f0e-6c1a-4e3b-9f7d-2a4c6e8b0d1f', '2023-02-18 12:00:02+00:00');",
+        )?;
+
+        migrate(&mut conn)?;
+
+        let mut stmt = conn.prepare("SELECT envelope FROM outbox ORDER BY id")?;
+        let envelopes = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .map(|envelope| Ok(serde_json::from_str::<MessageEnvelope>(&envelope?)?))
+            .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+        assert_eq!(
+            vec![
+                MessageEnvelope::new(
+                    DomainEvent::EmailChangeEvent {
+                        user_id: 1,
+                        new_email: "new@example.com".to_owned(),
+                    },
+                    "f0a2f6d2-3e43-4a36-8d0b-0c2e3c1f5b7a".parse()?,
+                    "2023-02-18T12:00:00.500Z".parse()?,
+                ),
+                MessageEnvelope::new(
+                    DomainEvent::UserTypeChangeEvent {
+                        user_id: 1,
+                        old_type: UserType::Employee,
+                        new_type: UserType::Cusotmer,
+                    },
+                    "0d3c1e6f5a2b4c7d8e9f0a1b2c3d4e5f".parse()?,
+                    "2023-02-18T12:00:01Z".parse()?,
+                ),
+                MessageEnvelope::new(
+                    DomainEvent::EmployeeCountCorrectedEvent {
+                        company_id: 1,
+                        old_count: 3,
+                        new_count: 2,
+                    },
+                    "5b2d8f0e-6c1a-4e3b-9f7d-2a4c6e8b0d1f".parse()?,
+                    "2023-02-18T12:00:02Z".parse()?,
+                ),
+            ],
+            envelopes
+        );
+
+        Ok(())
+    }
+}